@@ -0,0 +1,72 @@
+//! Adapter write benchmark: many threads logging through one adapter at once, which only
+//! contend on the channel to the writer
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use flexi_logger::writers::LogWriter;
+use flexi_logger::DeferredNow;
+use flexi_logger_datadog::adapter::DataDogAdapter;
+use flexi_logger_datadog::config::DataDogConfigBuilder;
+use log::{Level, Record};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Logging threads per benchmark
+const THREADS: [usize; 4] = [1, 2, 4, 8];
+
+fn concurrent_writes(c: &mut Criterion) {
+    let config = DataDogConfigBuilder::new(
+        "host".to_string(),
+        "bench".to_string(),
+        "dummy_key".to_string(),
+    )
+    .build();
+    let (logs, log_receiver) = flume::unbounded();
+    let (flush_request, _) = flume::bounded(0);
+    let (_, flush_response) = flume::bounded(0);
+    let adapter = DataDogAdapter::new(&config, logs, flush_request, flush_response);
+    // Stands in for the writer, so the channel doesn't grow without bound
+    let drain = thread::spawn(move || log_receiver.iter().count());
+
+    let mut group = c.benchmark_group("adapter write");
+    for threads in THREADS {
+        group.throughput(Throughput::Elements(threads as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &n| {
+            // Every thread writes `iters` lines, timed until the slowest has finished
+            b.iter_custom(|iters| {
+                thread::scope(|scope| {
+                    let writers = (0..n)
+                        .map(|_| {
+                            scope.spawn(|| {
+                                let started = Instant::now();
+                                for i in 0..iters {
+                                    adapter
+                                        .write(
+                                            &mut DeferredNow::new(),
+                                            &Record::builder()
+                                                .level(Level::Info)
+                                                .args(format_args!("line {}", i))
+                                                .build(),
+                                        )
+                                        .expect("failed to write");
+                                }
+                                started.elapsed()
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    writers
+                        .into_iter()
+                        .map(|w| w.join().expect("writer thread panicked"))
+                        .max()
+                        .unwrap_or(Duration::ZERO)
+                })
+            })
+        });
+    }
+    group.finish();
+
+    drop(adapter);
+    drain.join().expect("drain thread panicked");
+}
+
+criterion_group!(benches, concurrent_writes);
+criterion_main!(benches);
@@ -0,0 +1,37 @@
+//! Send path benchmark: building the request for a single line body, the per request setup
+//! (url, query and headers) that is paid on every send, without the network round trip
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use flexi_logger_datadog::config::DataDogConfigBuilder;
+use flexi_logger_datadog::writer::DataDogHttpWriter;
+use std::hint::black_box;
+
+fn build_request(c: &mut Criterion) {
+    let mut config = DataDogConfigBuilder::new(
+        "host".to_string(),
+        "bench".to_string(),
+        "dummy_key".to_string(),
+    );
+    config
+        .with_api_host(Some("http://localhost:8080/api/v2/logs".to_string()))
+        .with_tags(vec![("env", "bench"), ("team", "logging")]);
+
+    let (_, logs) = flume::unbounded();
+    let (_, flush_request) = flume::bounded(0);
+    let (flush_response, _) = flume::bounded(0);
+    let mut writer =
+        DataDogHttpWriter::new(config.build(), None, logs, flush_request, flush_response)
+            .expect("failed to build writer");
+    let line = b"INFO [bench] a single line".to_vec();
+
+    c.bench_function("send/build request", |b| {
+        b.iter(|| {
+            writer
+                .build_request(black_box(line.clone()))
+                .expect("failed to build request")
+        })
+    });
+}
+
+criterion_group!(benches, build_request);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+//! Captures the rustc version for the host metadata tags
+
+use std::env;
+use std::process::Command;
+
+fn main() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .and_then(|v| v.split_whitespace().nth(1).map(|s| s.to_string()))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!(
+        "cargo:rustc-env=FLEXI_LOGGER_DATADOG_RUSTC_VERSION={}",
+        version
+    );
+    println!("cargo:rerun-if-env-changed=RUSTC");
+}
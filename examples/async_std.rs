@@ -0,0 +1,21 @@
+use flexi_logger_datadog::config::DataDogConfigBuilder;
+use flexi_logger_datadog::init_async_std_logger;
+use log::{debug, error, info, trace};
+
+fn main() {
+    async_std::task::block_on(async {
+        let dd_config = DataDogConfigBuilder::new(
+            "logger-example".to_string(),
+            "logger-example".to_string(),
+            "DUMMY_API_KEY".to_string(),
+        )
+        .build();
+        let (logger, writer) = init_async_std_logger(dd_config, None).await.unwrap();
+        trace!("Trace message");
+        debug!("Debug message");
+        info!("Info message");
+        error!("Error message");
+        logger.shutdown();
+        writer.await;
+    })
+}
@@ -0,0 +1,17 @@
+use flexi_logger_datadog::config::DataDogConfigBuilder;
+use flexi_logger_datadog::init_tokio_logger;
+use log::info;
+
+#[tokio::main]
+async fn main() {
+    // Send as `ddsource:python` so the logs go through the same pipeline as the python services
+    let dd_config = DataDogConfigBuilder::new(
+        "logger-example".to_string(),
+        "logger-example".to_string(),
+        "DUMMY_API_KEY".to_string(),
+    )
+    .with_source("python")
+    .build();
+    init_tokio_logger(dd_config, None).await.unwrap();
+    info!("Info message");
+}
@@ -1,18 +1,34 @@
 //! Writable adapter that manages communication with the async writer task
 
-use crate::error::Error::{AdapterShutdownError, LockError};
+use crate::config::{
+    DataDogConfig, FilterResult, FormatFunction, LevelRouting, LogFormat, OverflowPolicy,
+    PayloadFormat, RecordFilter, SendFailurePolicy, SourceMapping, TextLayout, TimestampPrecision,
+    TraceContext,
+};
+use crate::error::Error::{AdapterShutdownError, FlushTimeout, LockError, WriterGone};
 use crate::error::{log_error, Error};
+use crate::event::{target_matches, LogEvent};
+use crate::stats::{BufferDepth, WriterStats};
+use arc_swap::ArcSwapOption;
+use chrono::{DateTime, Duration, Utc};
 use flexi_logger::writers::LogWriter;
 use flexi_logger::DeferredNow;
-use log::Record;
+use flume::{RecvTimeoutError, SendError, SendTimeoutError, TrySendError};
+use log::kv::{self, Key, VisitSource};
+use log::{warn, Level, LevelFilter, Record};
+use serde_json::{json, Map, Value};
 use std::io;
 use std::io::ErrorKind;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time;
 
 /// Channel for sending log messages
 struct LogStream {
     /// Log send channel
-    logs: flume::Sender<String>,
+    logs: flume::Sender<LogEvent>,
+    /// Receiving end of the log channel, used to discard the oldest line when it is full
+    oldest: Option<flume::Receiver<LogEvent>>,
 }
 
 /// Encapsulation of flush request/response channels
@@ -20,99 +36,556 @@ struct FlushStream {
     /// Flush request channel
     request: flume::Sender<()>,
     /// Flush response channel
-    response: flume::Receiver<Result<(), Error>>,
+    response: flume::Receiver<Result<usize, Error>>,
 }
 
-/// Writable adapter that manages communication with the async writer task
+/// DataDog statuses of syslog severities 0 to 7
+const SYSLOG_STATUSES: [&str; 8] = [
+    "emerg", "alert", "critical", "error", "warn", "notice", "info", "debug",
+];
+
+/// Target prefix of records logged by this crate
+const INTERNAL_TARGET: &str = env!("CARGO_CRATE_NAME");
+
+/// Whether the record was logged by this crate
+fn is_internal(record: &Record) -> bool {
+    target_matches(record.target(), INTERNAL_TARGET)
+}
+
+/// Collects a record's key-values as JSON attributes
+struct KeyValues(Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for KeyValues {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = value
+            .to_bool()
+            .map(Value::from)
+            .or_else(|| value.to_i64().map(Value::from))
+            .or_else(|| value.to_u64().map(Value::from))
+            .or_else(|| {
+                value
+                    .to_f64()
+                    .and_then(|f| serde_json::Number::from_f64(f).map(Value::from))
+            })
+            .unwrap_or_else(|| Value::from(value.to_string()));
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Structured key-values attached to record
+fn key_values(record: &Record) -> Map<String, Value> {
+    let mut key_values = KeyValues(Map::new());
+    if let Err(e) = record.key_values().visit(&mut key_values) {
+        log_error(e);
+    }
+    key_values.0
+}
+
+/// Writable adapter that manages communication with the async writer task.
+///
+/// Records logged by this crate itself are never sent, since failures to send would otherwise
+/// generate more logs to send
 pub struct DataDogAdapter {
     /// Log channel
-    log_channel: Mutex<Option<LogStream>>,
+    log_channel: ArcSwapOption<LogStream>,
     /// Flush channels
     flush_channel: Mutex<Option<FlushStream>>,
+    /// Format of request bodies
+    payload_format: PayloadFormat,
+    /// DataDog statuses overriding the default for each level
+    status_mapping: Vec<(Level, String)>,
+    /// Key-value whose numeric syslog severity sets the DataDog status
+    status_from_kv: Option<String>,
+    /// Format of each log line
+    log_format: LogFormat,
+    /// Layout of text log lines
+    text_layout: TextLayout,
+    /// Trailing module path segments kept
+    module_path_depth: Option<usize>,
+    /// Prefix removed from module paths
+    module_path_strip_prefix: Option<String>,
+    /// Remove a single trailing newline from each line
+    trim_trailing_newline: bool,
+    /// Prepend text lines with the time they were logged
+    line_timestamp: bool,
+    /// Precision of line timestamps
+    timestamp_precision: TimestampPrecision,
+    /// Function formatting records in place of the built in formats
+    formatter: Option<FormatFunction>,
+    /// Function choosing the source of each record
+    source_mapping: Option<SourceMapping>,
+    /// Function routing records of each level to another service or tags
+    level_routing: Option<LevelRouting>,
+    /// Function extracting the trace and span ids of each record
+    trace_context: Option<TraceContext>,
+    /// Filter applied to records before they are sent
+    filter: Option<RecordFilter>,
+    /// Behavior when a log line cannot be sent to the writer
+    send_failure_policy: SendFailurePolicy,
+    /// Behavior when a bounded log channel is full
+    overflow_policy: OverflowPolicy,
+    /// Channel fill ratio at which backpressure is reported
+    high_water_mark: Option<f64>,
+    /// Whether the channel is past its high-water mark, so crossing it is reported once
+    above_high_water: AtomicBool,
+    /// Longest a flush waits for the writer
+    flush_timeout: Option<time::Duration>,
+    /// Longest the final flush on shutdown waits for the writer
+    shutdown_timeout: Option<time::Duration>,
+    /// Counters shared with the writer
+    stats: Arc<WriterStats>,
+    /// Most verbose level sent, as a [`LevelFilter`] discriminant
+    min_level: AtomicUsize,
+    /// Wakes the writer to flush without waiting for the result
+    flush_signal: flume::Sender<()>,
 }
 
 impl DataDogAdapter {
     /// Create new [`DataDogAdapter`] with channels
     pub fn new(
-        logs: flume::Sender<String>,
+        datadog_config: &DataDogConfig,
+        logs: flume::Sender<LogEvent>,
         flush_request: flume::Sender<()>,
-        flush_response: flume::Receiver<Result<(), Error>>,
+        flush_response: flume::Receiver<Result<usize, Error>>,
     ) -> Self {
         Self {
-            log_channel: Mutex::new(Some(LogStream { logs })),
+            log_channel: ArcSwapOption::from_pointee(LogStream { logs, oldest: None }),
             flush_channel: Mutex::new(Some(FlushStream {
                 request: flush_request,
                 response: flush_response,
             })),
+            payload_format: datadog_config.payload_format,
+            status_mapping: datadog_config.status_mapping.clone(),
+            status_from_kv: datadog_config.status_from_kv.clone(),
+            log_format: datadog_config.log_format,
+            text_layout: datadog_config.text_layout,
+            module_path_depth: datadog_config.module_path_depth,
+            module_path_strip_prefix: datadog_config.module_path_strip_prefix.clone(),
+            trim_trailing_newline: datadog_config.trim_trailing_newline,
+            line_timestamp: datadog_config.line_timestamp,
+            timestamp_precision: datadog_config.timestamp_precision,
+            formatter: datadog_config.formatter.clone(),
+            source_mapping: datadog_config.source_mapping.clone(),
+            level_routing: datadog_config.level_routing.clone(),
+            trace_context: datadog_config.trace_context.clone(),
+            filter: datadog_config.filter.clone(),
+            send_failure_policy: datadog_config.send_failure_policy,
+            overflow_policy: datadog_config.overflow_policy,
+            high_water_mark: datadog_config.high_water_mark,
+            above_high_water: AtomicBool::new(false),
+            flush_timeout: datadog_config
+                .flush_timeout
+                .map(|t| t.to_std().unwrap_or_default()),
+            shutdown_timeout: datadog_config
+                .shutdown_timeout
+                .map(|t| t.to_std().unwrap_or_default()),
+            stats: Arc::new(WriterStats::default()),
+            min_level: AtomicUsize::new(datadog_config.min_level as usize),
+            flush_signal: flume::bounded(1).0,
         }
     }
-}
 
-impl LogWriter for DataDogAdapter {
-    fn write(&self, _now: &mut DeferredNow, record: &Record) -> io::Result<()> {
+    /// Share counters with the writer this adapter sends to
+    pub(crate) fn with_stats(mut self, stats: Arc<WriterStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Give the adapter the receiving end of its log channel, so [`OverflowPolicy::DropOldest`]
+    /// can discard the oldest line when the channel is full. Ignored under other policies, where
+    /// holding a receiver would hide a writer that has stopped
+    pub(crate) fn with_overflow_receiver(self, receiver: flume::Receiver<LogEvent>) -> Self {
+        if self.overflow_policy == OverflowPolicy::DropOldest {
+            if let Some(stream) = &*self.log_channel.load() {
+                self.log_channel.store(Some(Arc::new(LogStream {
+                    logs: stream.logs.clone(),
+                    oldest: Some(receiver),
+                })));
+            }
+        }
+        self
+    }
+
+    /// Signal the writer this adapter sends to on [`Self::request_flush`]
+    pub(crate) fn with_flush_signal(mut self, flush_signal: flume::Sender<()>) -> Self {
+        self.flush_signal = flush_signal;
+        self
+    }
+
+    /// How full the bounded channel to the writer is, from 0 to 1. Always 0 for an unbounded
+    /// channel or after shutdown. A value staying near 1 means logs are produced faster than
+    /// they can be sent, so callers may want to shed load
+    pub fn pressure(&self) -> f64 {
         self.log_channel
-            .lock()
-            .map_err(|e| {
-                io::Error::new(
-                    ErrorKind::BrokenPipe,
-                    LockError(format!("Failed to acquire logs lock: {}", e)),
-                )
-            })
-            .and_then(|maybe_logs| match &*maybe_logs {
-                None => Err(io::Error::new(ErrorKind::BrokenPipe, AdapterShutdownError)),
-                Some(stream) => {
-                    let log = format!(
-                        "{} [{}] {}",
-                        record.level(),
-                        record.module_path().unwrap_or_default(),
-                        record.args()
-                    );
-                    stream
-                        .logs
-                        .send(log)
-                        .map_err(|e| io::Error::new(ErrorKind::BrokenPipe, e))?;
-                    Ok(())
+            .load()
+            .as_ref()
+            .map_or(0.0, |stream| Self::fill_ratio(stream))
+    }
+
+    /// Fill ratio of the channel of `stream`, 0 if it is unbounded
+    fn fill_ratio(stream: &LogStream) -> f64 {
+        match stream.logs.capacity() {
+            Some(capacity) if capacity > 0 => stream.logs.len() as f64 / capacity as f64,
+            _ => 0.0,
+        }
+    }
+
+    /// Warn and count when the channel first fills past the high-water mark, again only after
+    /// it has dropped back below it
+    fn check_pressure(&self, stream: &LogStream) {
+        let mark = match self.high_water_mark {
+            Some(mark) => mark,
+            None => return,
+        };
+        let ratio = Self::fill_ratio(stream);
+        if ratio < mark {
+            self.above_high_water.store(false, Ordering::Relaxed);
+        } else if ratio > 0.0 && !self.above_high_water.swap(true, Ordering::Relaxed) {
+            self.stats.record_high_water();
+            warn!(
+                "DataDog log channel is {:.0}% full, logs are produced faster than they are sent",
+                ratio * 100.0
+            );
+        }
+    }
+
+    /// Counters describing the writer, e.g. how many lines were evicted from its buffer
+    pub fn stats(&self) -> Arc<WriterStats> {
+        self.stats.clone()
+    }
+
+    /// Lines waiting to be sent, both in the channel to the writer and in its buffer
+    pub fn buffer_depth(&self) -> BufferDepth {
+        BufferDepth {
+            queued_lines: self
+                .log_channel
+                .load()
+                .as_ref()
+                .map_or(0, |stream| stream.logs.len()),
+            buffered_lines: self.stats.buffered_lines(),
+            buffered_bytes: self.stats.buffered_bytes(),
+        }
+    }
+
+    /// Flush buffered logs, returning the number of lines sent. `0` means there was nothing
+    /// to send
+    pub fn flush_lines(&self) -> io::Result<usize> {
+        self.flush_within(self.flush_timeout)
+    }
+
+    /// Flush buffered logs and wait up to `timeout` for the writer to send them, returning the
+    /// number of lines sent. Fails with [`FlushTimeout`] if the writer hasn't responded in time,
+    /// [`WriterGone`] if it has stopped, or the writer's error if sending failed
+    pub fn flush_blocking(&self, timeout: Option<Duration>) -> Result<usize, Error> {
+        self.flush_until(timeout.map(|t| time::Instant::now() + t.to_std().unwrap_or_default()))
+    }
+
+    /// Flush buffered logs, failing with [`ErrorKind::TimedOut`] if the writer hasn't responded
+    /// within `timeout`
+    fn flush_within(&self, timeout: Option<time::Duration>) -> io::Result<usize> {
+        self.flush_until(timeout.map(|t| time::Instant::now() + t))
+            .map_err(|e| match e {
+                FlushTimeout => io::Error::new(ErrorKind::TimedOut, e),
+                LockError(_) | AdapterShutdownError | WriterGone => {
+                    io::Error::new(ErrorKind::BrokenPipe, e)
                 }
+                e => io::Error::other(e),
             })
     }
 
-    fn flush(&self) -> io::Result<()> {
-        self.flush_channel
+    /// Ask the writer to flush and wait for its response until `deadline`, if any
+    fn flush_until(&self, deadline: Option<time::Instant>) -> Result<usize, Error> {
+        let guard = self
+            .flush_channel
             .try_lock()
-            .map_err(|_| {
-                io::Error::new(
-                    ErrorKind::BrokenPipe,
-                    LockError("Failed to acquire flush lock".to_string()),
-                )
-            })
-            .and_then(|maybe_flush| match &*maybe_flush {
-                None => Err(io::Error::new(ErrorKind::BrokenPipe, AdapterShutdownError)),
-                Some(stream) => {
-                    stream
-                        .request
-                        .send(())
-                        .map_err(|e| io::Error::new(ErrorKind::BrokenPipe, e))?;
-                    let r = stream
-                        .response
-                        .recv()
-                        .map_err(|e| io::Error::new(ErrorKind::BrokenPipe, e))?;
-                    r.map_err(|e| io::Error::new(ErrorKind::Other, e))
+            .map_err(|_| LockError("Failed to acquire flush lock".to_string()))?;
+        let stream = guard.as_ref().ok_or(AdapterShutdownError)?;
+        match deadline {
+            None => {
+                stream.request.send(()).map_err(|_| WriterGone)?;
+                stream.response.recv().map_err(|_| WriterGone)?
+            }
+            Some(deadline) => {
+                stream
+                    .request
+                    .send_deadline((), deadline)
+                    .map_err(|e| match e {
+                        SendTimeoutError::Timeout(_) => FlushTimeout,
+                        SendTimeoutError::Disconnected(_) => WriterGone,
+                    })?;
+                stream
+                    .response
+                    .recv_deadline(deadline)
+                    .map_err(|e| match e {
+                        RecvTimeoutError::Timeout => FlushTimeout,
+                        RecvTimeoutError::Disconnected => WriterGone,
+                    })?
+            }
+        }
+    }
+
+    /// Ask the writer to flush and return immediately, without waiting for the lines to be sent
+    /// or learning whether sending succeeded. Requests made before the writer gets to them are
+    /// combined into one flush. Use [`Self::flush_lines`] to wait for the result
+    pub fn request_flush(&self) -> io::Result<()> {
+        if self.log_channel.load().is_none() {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, AdapterShutdownError));
+        }
+        // A full signal channel already has a flush pending, which this request joins
+        let _ = self.flush_signal.try_send(());
+        Ok(())
+    }
+
+    /// Change the most verbose level sent to DataDog while running, e.g. to capture debug logs
+    /// during an incident. The `log` macros discard records more verbose than
+    /// [`log::max_level`], which the logger only sets when it starts, so it is raised to `level`
+    /// if below it, and never lowered. Records must also pass the logger's own level
+    /// specification
+    pub fn set_min_level(&self, level: LevelFilter) {
+        self.min_level.store(level as usize, Ordering::Relaxed);
+        if level > log::max_level() {
+            log::set_max_level(level);
+        }
+    }
+
+    /// Most verbose level currently sent to DataDog
+    pub fn min_level(&self) -> LevelFilter {
+        let level = self.min_level.load(Ordering::Relaxed);
+        LevelFilter::iter()
+            .find(|l| *l as usize == level)
+            .unwrap_or(LevelFilter::Trace)
+    }
+
+    /// Send event to the writer according to the [`OverflowPolicy`], failing if the writer is gone
+    fn send(&self, stream: &LogStream, mut event: LogEvent) -> Result<(), SendError<()>> {
+        if self.overflow_policy == OverflowPolicy::Block {
+            return stream.logs.send(event).map_err(|_| SendError(()));
+        }
+        loop {
+            match stream.logs.try_send(event) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(_)) => return Err(SendError(())),
+                Err(TrySendError::Full(e)) => match &stream.oldest {
+                    // The writer may take the oldest line first, in which case simply retry
+                    Some(oldest) => {
+                        if oldest.try_recv().is_ok() {
+                            self.stats.record_overflow();
+                        }
+                        event = e;
+                    }
+                    None => {
+                        self.stats.record_overflow();
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
+
+    /// DataDog status for records of `level`
+    fn status(&self, level: Level) -> String {
+        self.status_mapping
+            .iter()
+            .find(|(l, _)| *l == level)
+            .map(|(_, status)| status.clone())
+            .unwrap_or_else(|| level.as_str().to_lowercase())
+    }
+
+    /// DataDog status of the syslog severity in the configured key-value, if any. Values that
+    /// aren't a severity from 0 to 7 are warned about and ignored
+    fn severity_status(&self, key_values: &Map<String, Value>) -> Option<String> {
+        let key = self.status_from_kv.as_deref()?;
+        let value = key_values.get(key)?;
+        let severity = match value {
+            Value::String(s) => s.trim().parse::<u64>().ok(),
+            value => value.as_u64(),
+        };
+        match severity.and_then(|s| SYSLOG_STATUSES.get(s as usize)) {
+            Some(status) => Some(status.to_string()),
+            None => {
+                warn!(
+                    "Invalid syslog severity {} in `{}`, using the level's status",
+                    value, key
+                );
+                None
+            }
+        }
+    }
+
+    /// Module path of record with the configured prefix and depth trimming applied
+    fn module_path<'a>(&self, record: &Record<'a>) -> &'a str {
+        let mut module = record.module_path().unwrap_or_default();
+        if let Some(prefix) = self.module_path_strip_prefix.as_deref() {
+            let prefix = prefix.trim_end_matches("::");
+            if let Some(stripped) = module
+                .strip_prefix(prefix)
+                .and_then(|m| m.strip_prefix("::"))
+            {
+                module = stripped;
+            }
+        }
+        if let Some(depth) = self.module_path_depth {
+            let segments = module.split("::").count();
+            if depth > 0 && segments > depth {
+                module = module
+                    .splitn(segments - depth + 1, "::")
+                    .last()
+                    .unwrap_or(module);
+            }
+        }
+        module
+    }
+
+    /// Format record according to the configured [`LogFormat`] and [`TextLayout`], including
+    /// `key_values` as top-level fields of JSON lines or `key=value` pairs after the message
+    fn format(
+        &self,
+        record: &Record,
+        timestamp: DateTime<Utc>,
+        key_values: Map<String, Value>,
+    ) -> String {
+        let level = record.level();
+        let module = self.module_path(record);
+        let args = record.args();
+        if self.log_format == LogFormat::JsonLines {
+            let mut line = json!({
+                "date": timestamp.to_rfc3339_opts(self.timestamp_precision.seconds_format(), true),
+                "status": level.as_str().to_lowercase(),
+                "logger": { "name": module },
+                "message": args.to_string(),
+            });
+            if let Value::Object(fields) = &mut line {
+                for (key, value) in key_values {
+                    fields.entry(key).or_insert(value);
                 }
-            })
+            }
+            return line.to_string();
+        }
+        let mut line = match self.text_layout {
+            TextLayout::Brackets => format!("{} [{}] {}", level, module, args),
+            TextLayout::Colon => format!("{} {}: {}", level, module, args),
+            TextLayout::Pipe => format!("{}|{}|{}", level, module, args),
+        };
+        if self.line_timestamp {
+            line.insert_str(
+                0,
+                &format!(
+                    "{} ",
+                    timestamp.to_rfc3339_opts(self.timestamp_precision.seconds_format(), true)
+                ),
+            );
+        }
+        for (key, value) in key_values {
+            let value = match value {
+                Value::String(value) => value,
+                value => value.to_string(),
+            };
+            line.push_str(&format!(" {}={}", key, value));
+        }
+        line
+    }
+}
+
+impl LogWriter for DataDogAdapter {
+    fn write(&self, now: &mut DeferredNow, record: &Record) -> io::Result<()> {
+        if is_internal(record) || record.level() > self.min_level() {
+            return Ok(());
+        }
+        // Lock free, concurrent writers only contend on the channel itself
+        match &*self.log_channel.load() {
+            None => Err(io::Error::new(ErrorKind::BrokenPipe, AdapterShutdownError)),
+            Some(stream) => {
+                let timestamp = now.now_utc_owned();
+                // JSON payloads carry key-values as attributes, otherwise they're part of the line
+                let mut key_values = key_values(record);
+                if let Some(ids) = self.trace_context.as_ref().and_then(|f| f(record)) {
+                    key_values.insert("dd.trace_id".to_string(), ids.trace_id.into());
+                    key_values.insert("dd.span_id".to_string(), ids.span_id.into());
+                }
+                let (inline, attributes) = match self.payload_format {
+                    PayloadFormat::Text => (key_values, Map::new()),
+                    PayloadFormat::OtlpJson => (Map::new(), key_values),
+                    PayloadFormat::Json => {
+                        let mut attributes = key_values;
+                        let status = self
+                            .severity_status(&attributes)
+                            .unwrap_or_else(|| self.status(record.level()));
+                        attributes.entry("status").or_insert_with(|| status.into());
+                        (Map::new(), attributes)
+                    }
+                };
+                let mut log = match self.filter.as_ref().map(|f| f(record)) {
+                    None | Some(FilterResult::Keep) => match &self.formatter {
+                        Some(formatter) => formatter(now, record),
+                        None => self.format(record, timestamp, inline),
+                    },
+                    Some(FilterResult::Drop) => return Ok(()),
+                    Some(FilterResult::Rewrite(line)) => line,
+                };
+                if self.trim_trailing_newline && log.ends_with('\n') {
+                    log.pop();
+                    if log.ends_with('\r') {
+                        log.pop();
+                    }
+                }
+                let event = LogEvent::new(log, record.target().to_string(), timestamp)
+                    .with_attributes(attributes)
+                    .with_source(self.source_mapping.as_ref().and_then(|f| f(record)))
+                    .with_route(self.level_routing.as_ref().and_then(|f| f(record.level())))
+                    .with_level(record.level());
+                let sent = self.send(stream, event);
+                self.check_pressure(stream);
+                match (sent, self.send_failure_policy) {
+                    (Ok(_), _) | (Err(_), SendFailurePolicy::SilentDrop) => Ok(()),
+                    (Err(e), SendFailurePolicy::ReturnErr) => {
+                        Err(io::Error::new(ErrorKind::BrokenPipe, e))
+                    }
+                    (Err(e), SendFailurePolicy::Stderr) => {
+                        eprintln!("Failed to send log line to DataDog writer: {}", e);
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.flush_lines().map(|_| ())
+    }
+
+    fn max_log_level(&self) -> LevelFilter {
+        self.min_level()
     }
 
     fn shutdown(&self) {
-        if let Err(e) = self.flush() {
-            log_error(e);
+        let deadline = self
+            .shutdown_timeout
+            .or(self.flush_timeout)
+            .map(|t| time::Instant::now() + t);
+        match self.flush_until(deadline) {
+            Ok(_) => (),
+            // The writer's runtime may have ended first, dropping the writer along with it.
+            // Nothing is lost if no lines were queued, e.g. when the writer failed to start
+            Err(WriterGone) => match self
+                .log_channel
+                .load()
+                .as_ref()
+                .map_or(0, |stream| stream.logs.len())
+            {
+                0 => (),
+                queued => log_error(format!(
+                    "Writer stopped before the final flush, {} queued log lines were not sent",
+                    queued
+                )),
+            },
+            Err(e) => log_error(e),
         }
         match self.flush_channel.try_lock() {
             Ok(mut flush) => std::mem::drop(flush.take()),
             Err(e) => log_error(e),
         }
-        match self.log_channel.try_lock() {
-            Ok(mut logs) => std::mem::drop(logs.take()),
-            Err(e) => log_error(e),
-        }
+        self.log_channel.store(None);
     }
 }
 
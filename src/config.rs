@@ -1,18 +1,398 @@
 //! Configuration structs
 //! Defaults pulled from https://docs.datadoghq.com/api/latest/logs/#send-logs
 
+use crate::error::Error;
+use crate::error::Error::{ConfigError, MissingEnvVar};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use flexi_logger::DeferredNow;
 use itertools::Itertools;
+use log::{warn, Level, LevelFilter, Record};
+use reqwest::header::HeaderName;
+use reqwest::Client;
+use serde_json::{Map, Value};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
 
-/// Default log api URL
-const DEFAULT_DATADOG_INGEST_URL: &str = "https://http-intake.logs.datadoghq.com/api/v2/logs";
 /// Maximum request size DataDog api will accept
-const DEFAULT_MAX_PAYLOAD_BYTES: usize = 5000000;
+pub(crate) const MAX_PAYLOAD_BYTES: usize = 5000000;
+/// `ddsource` used unless [`DataDogConfigBuilder::with_source`] sets another
+pub const DEFAULT_SOURCE: &str = "rust";
+/// Header the api key is sent in unless [`DataDogConfigBuilder::with_api_key_header`] sets
+/// another
+pub const DEFAULT_API_KEY_HEADER: &str = "DD-API-KEY";
+/// Placeholder replaced by the api key in [`DataDogConfigBuilder::with_api_key_template`]
+pub const API_KEY_PLACEHOLDER: &str = "{api_key}";
 /// Maximum bytes to buffer before sending to DataDog
-const DEFAULT_BODY_SEND_BYTES: usize = ((DEFAULT_MAX_PAYLOAD_BYTES as f64) * 0.75f64) as usize;
+const DEFAULT_BODY_SEND_BYTES: usize = ((MAX_PAYLOAD_BYTES as f64) * 0.75f64) as usize;
+/// Default delay before the first retry of a failed request
+const DEFAULT_RETRY_BASE_DELAY_MS: i64 = 100;
+/// Default cap on the delay requested by a `Retry-After` header
+const DEFAULT_MAX_RETRY_AFTER_SECS: i64 = 60;
+/// Default longest a request to the api may take
+const DEFAULT_REQUEST_TIMEOUT_SECS: i64 = 10;
+/// Variable the DataDog agent reads the environment from
+const DD_ENV_VAR: &str = "DD_ENV";
+/// Variable the DataDog agent reads the api key from
+const DD_API_KEY_VAR: &str = "DD_API_KEY";
+/// Variable the DataDog agent reads the site from
+const DD_SITE_VAR: &str = "DD_SITE";
+/// Variable the DataDog agent reads the service from
+const DD_SERVICE_VAR: &str = "DD_SERVICE";
+/// Variable the DataDog agent reads the hostname from
+const DD_HOSTNAME_VAR: &str = "DD_HOSTNAME";
 /// Maximum number of log lines allowed in an array
 const DEFAULT_MAX_LOG_LINES: usize = 1000;
+/// Maximum size of a single log DataDog accepts
+const MAX_LINE_BYTES: usize = 1000000;
+/// Channel fill ratio at which backpressure is reported unless configured otherwise
+const DEFAULT_HIGH_WATER_MARK: f64 = 0.8;
+/// Default gzip compression level, balancing ratio and speed
+const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+/// Highest gzip compression level
+const MAX_COMPRESSION_LEVEL: u32 = 9;
+/// Maximum length of a DataDog tag
+const MAX_TAG_LENGTH: usize = 200;
+/// Version of rustc the crate was built with, captured by the build script
+const RUSTC_VERSION: &str = env!("FLEXI_LOGGER_DATADOG_RUSTC_VERSION");
+
+/// Value of environment variable `name`, [`None`] if it is unset or empty
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.trim().is_empty())
+}
+
+/// Tag as sent in `ddtags`, `key:value` or a bare `value` when the key is empty
+pub(crate) fn format_tag(key: &str, value: &str) -> String {
+    match key.is_empty() {
+        true => value.to_string(),
+        false => format!("{}:{}", key, value),
+    }
+}
+
+/// Tag sanitized per DataDog's tag rules: lowercased, characters other than alphanumerics and
+/// `_-./` (and `:` in values) replaced by `_`, and capped at [`MAX_TAG_LENGTH`]
+fn sanitize_tag(key: &str, value: &str) -> (String, String) {
+    let clean = |s: &str, allowed: &str| {
+        s.chars()
+            .flat_map(char::to_lowercase)
+            .map(|c| match c.is_alphanumeric() || allowed.contains(c) {
+                true => c,
+                false => '_',
+            })
+            .collect::<String>()
+    };
+    let key = clean(key, "_-./");
+    let mut value = clean(value, "_-./:");
+    let max_value =
+        MAX_TAG_LENGTH.saturating_sub(key.chars().count() + usize::from(!key.is_empty()));
+    if let Some((end, _)) = value.char_indices().nth(max_value) {
+        value.truncate(end);
+    }
+    (key, value)
+}
+
+/// Hostname reported by the OS, or `"unknown"` when it can't be read
+fn os_hostname() -> String {
+    match gethostname::gethostname().into_string() {
+        Ok(hostname) if !hostname.trim().is_empty() => hostname,
+        _ => {
+            warn!("Unable to resolve the OS hostname, falling back to \"unknown\"");
+            "unknown".to_string()
+        }
+    }
+}
+
+/// Log api URL of the DataDog site at `domain`
+fn intake_url(domain: &str) -> String {
+    format!("https://http-intake.logs.{}/api/v2/logs", domain)
+}
+
+/// DataDog site, see https://docs.datadoghq.com/getting_started/site/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DataDogSite {
+    /// `datadoghq.com`
+    #[default]
+    US1,
+    /// `us3.datadoghq.com`
+    US3,
+    /// `us5.datadoghq.com`
+    US5,
+    /// `datadoghq.eu`
+    EU1,
+    /// `ap1.datadoghq.com`
+    AP1,
+    /// `ddog-gov.com`
+    US1Fed,
+}
+
+impl DataDogSite {
+    /// Domain of the site
+    pub fn domain(&self) -> &'static str {
+        match self {
+            DataDogSite::US1 => "datadoghq.com",
+            DataDogSite::US3 => "us3.datadoghq.com",
+            DataDogSite::US5 => "us5.datadoghq.com",
+            DataDogSite::EU1 => "datadoghq.eu",
+            DataDogSite::AP1 => "ap1.datadoghq.com",
+            DataDogSite::US1Fed => "ddog-gov.com",
+        }
+    }
+
+    /// Log api URL of the site
+    pub fn intake_url(&self) -> String {
+        intake_url(self.domain())
+    }
+}
+
+/// Layout of text log lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextLayout {
+    /// `{level} [{module}] {args}`
+    #[default]
+    Brackets,
+    /// `{level} {module}: {args}`
+    Colon,
+    /// `{level}|{module}|{args}`
+    Pipe,
+}
+
+/// Format of each log line, sent as is in [`PayloadFormat::Text`] or as the message of each log
+/// object in [`PayloadFormat::Json`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Plain text in the configured [`TextLayout`]
+    #[default]
+    Text,
+    /// Compact JSON object per line, which DataDog parses into attributes
+    JsonLines,
+}
+
+/// Format of request bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PayloadFormat {
+    /// Newline delimited `text/plain`, with host, service, source and tags in the query
+    #[default]
+    Text,
+    /// `application/json` array of log objects, each carrying its own host, service, source and
+    /// tags
+    Json,
+    /// OpenTelemetry `ExportLogsServiceRequest` in OTLP/JSON, with host, service, source and tags
+    /// as resource attributes, for an OpenTelemetry collector that forwards to DataDog. Point
+    /// the api host at the collector's `/v1/logs` endpoint
+    OtlpJson,
+}
+
+/// Precision of the timestamps sent with each line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimestampPrecision {
+    /// Whole seconds
+    Seconds,
+    /// Milliseconds, which DataDog expects
+    #[default]
+    Millis,
+    /// Nanoseconds
+    Nanos,
+}
+
+impl TimestampPrecision {
+    /// `timestamp` since the unix epoch in units of this precision
+    pub fn epoch(&self, timestamp: DateTime<Utc>) -> i64 {
+        match self {
+            TimestampPrecision::Seconds => timestamp.timestamp(),
+            TimestampPrecision::Millis => timestamp.timestamp_millis(),
+            TimestampPrecision::Nanos => timestamp.timestamp_nanos_opt().unwrap_or(i64::MAX),
+        }
+    }
+
+    /// Fractional seconds kept when formatting a timestamp as RFC 3339
+    pub fn seconds_format(&self) -> SecondsFormat {
+        match self {
+            TimestampPrecision::Seconds => SecondsFormat::Secs,
+            TimestampPrecision::Millis => SecondsFormat::Millis,
+            TimestampPrecision::Nanos => SecondsFormat::Nanos,
+        }
+    }
+}
+
+/// Gzip compression of request bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GzipMode {
+    /// Never compress
+    #[default]
+    Never,
+    /// Compress every request
+    Always,
+    /// Compress only requests whose uncompressed body is at least this many bytes
+    Auto(usize),
+}
+
+/// Compression of request bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Never compress
+    None,
+    /// Gzip requests according to the configured [`GzipMode`]
+    #[default]
+    Gzip,
+    /// Zstd compress every request
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// Behavior when a log line cannot be sent to the writer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SendFailurePolicy {
+    /// Return an error from the [`flexi_logger::writers::LogWriter`]
+    #[default]
+    ReturnErr,
+    /// Discard the line without reporting
+    SilentDrop,
+    /// Discard the line and report the failure on stderr
+    Stderr,
+}
+
+/// Behavior for log lines over the configured maximum line size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OversizedLinePolicy {
+    /// Discard the line, counting it in [`crate::stats::WriterStats::oversized_lines`]
+    Drop,
+    /// Cut the line to the maximum size on a char boundary, ending it with `...[truncated]`, so
+    /// its start still reaches DataDog
+    #[default]
+    Truncate,
+}
+
+/// Behavior when a bounded channel between the adapter and writer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Block the logging thread until the writer makes room, losing no lines
+    #[default]
+    Block,
+    /// Discard the line being logged, never blocking the logging thread
+    DropNewest,
+    /// Discard the oldest line in the channel to make room, never blocking the logging thread
+    DropOldest,
+}
+
+/// When the writer replaces its HTTP client with a fresh one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientRecycle {
+    /// After the client has existed for this long
+    Interval(Duration),
+    /// After the client has made this many requests
+    Requests(usize),
+}
+
+/// Outcome of a [`RecordFilter`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterResult {
+    /// Send the record as normal
+    Keep,
+    /// Do not send the record
+    Drop,
+    /// Send this line in place of the formatted record
+    Rewrite(String),
+}
+
+/// Function deciding whether and how a record is sent.
+///
+/// Runs on the logging thread for every record, so it must be cheap
+pub type RecordFilter = Arc<dyn Fn(&Record) -> FilterResult + Send + Sync>;
+
+/// Function formatting a record into the line sent to DataDog.
+///
+/// Runs on the logging thread for every record, so it must be cheap
+pub type FormatFunction = Arc<dyn Fn(&mut DeferredNow, &Record) -> String + Send + Sync>;
+
+/// Function choosing the `ddsource` of a record, [`None`] for the configured source
+pub type SourceMapping = Arc<dyn Fn(&Record) -> Option<String> + Send + Sync>;
+
+/// DataDog APM ids of the trace and span a record was logged in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceIds {
+    /// Trace id, in DataDog's decimal format
+    pub trace_id: String,
+    /// Span id, in DataDog's decimal format
+    pub span_id: String,
+}
+
+/// Service and tags overriding the configured ones for records of a level
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LevelRoute {
+    /// Service replacing the configured service, [`None`] to keep it
+    pub service: Option<String>,
+    /// Tags added to the configured tags, an empty key is a key-less tag
+    pub tags: Vec<(String, String)>,
+}
+
+/// Function choosing the [`LevelRoute`] of records of a level, [`None`] for the configured
+/// service and tags
+pub type LevelRouting = Arc<dyn Fn(Level) -> Option<LevelRoute> + Send + Sync>;
+
+/// Size limits applied together with [`DataDogConfigBuilder::with_limits`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataDogLimits {
+    /// Maximum log lines in a single request
+    pub max_log_lines: usize,
+    /// Maximum size of a log line in bytes
+    pub max_line_size: usize,
+    /// Maximum bytes buffered before flushing
+    pub max_payload_size: usize,
+}
+
+impl DataDogLimits {
+    /// The maxima DataDog documents: 1000 lines per request, 1MB per line, and 75% of the 5MB
+    /// request limit buffered
+    pub fn datadog_defaults() -> Self {
+        Self {
+            max_log_lines: DEFAULT_MAX_LOG_LINES,
+            max_line_size: MAX_LINE_BYTES,
+            max_payload_size: DEFAULT_BODY_SEND_BYTES,
+        }
+    }
+
+    /// Smaller limits for self-hosted or proxied intakes with tighter request limits: 100 lines
+    /// per request, 64KB per line, and 1MB buffered
+    pub fn conservative() -> Self {
+        Self {
+            max_log_lines: 100,
+            max_line_size: 64 * 1024,
+            max_payload_size: 1000000,
+        }
+    }
+
+    /// Check the limits are non-zero and a line fits in the payload
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.max_log_lines == 0 || self.max_line_size == 0 || self.max_payload_size == 0 {
+            return Err(ConfigError(format!("Limits must be non-zero: {:?}", self)));
+        }
+        if self.max_line_size > self.max_payload_size {
+            return Err(ConfigError(format!(
+                "Max line size {} exceeds the max payload size {}, every larger line would be dropped",
+                self.max_line_size, self.max_payload_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Function extracting the [`TraceIds`] of a record, [`None`] outside of a trace
+pub type TraceContext = Arc<dyn Fn(&Record) -> Option<TraceIds> + Send + Sync>;
+
+/// Function receiving each log line dropped for exceeding the maximum line size
+pub type DroppedLineCallback = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Function receiving each error the writer encounters
+pub type ErrorCallback = Arc<dyn Fn(&Error) + Send + Sync>;
+
+/// Function receiving the duration of each flush that sent logs
+pub type FlushLatencyCallback = Arc<dyn Fn(std::time::Duration) + Send + Sync>;
+
+/// Function returning the current api key, so it can be rotated without a restart
+pub type ApiKeyProvider = Arc<dyn Fn() -> String + Send + Sync>;
 
 /// DataDog api configuration
+#[derive(Clone)]
 pub struct DataDogConfig {
     /// The name of the originating host of the log
     pub hostname: String,
@@ -22,14 +402,142 @@ pub struct DataDogConfig {
     pub api_key: String,
     /// DataDog api url
     pub api_host: String,
-    /// Tags associated with logs
+    /// Tags associated with logs, an empty key is a key-less tag
     pub tags: Vec<(String, String)>,
     /// The integration name associated with your log
     pub source: String,
     /// Maximum log lines in a single request
     pub max_log_lines: usize,
-    /// Maximum allowed api request size
+    /// Bytes of buffered log lines that trigger a flush, after compression if bodies are
+    /// compressed
     pub max_payload_size: usize,
+    /// Warn once if the api's clock differs from the local clock by more than this
+    pub clock_skew_warning: Option<Duration>,
+    /// Layout of text log lines
+    pub text_layout: TextLayout,
+    /// Gzip compression of request bodies
+    pub gzip: GzipMode,
+    /// Filter applied to records before they are sent
+    pub filter: Option<RecordFilter>,
+    /// Behavior when a log line cannot be sent to the writer
+    pub send_failure_policy: SendFailurePolicy,
+    /// Local file every line is also written to, and its maximum size before rotation
+    pub local_tee: Option<(PathBuf, usize)>,
+    /// Pick compression from the api's advertised `Accept-Encoding`
+    pub negotiated_compression: bool,
+    /// When to replace the HTTP client with a fresh one
+    pub client_recycle: Option<ClientRecycle>,
+    /// Stop sending logs after repeated authentication failures
+    pub auth_failure_shutdown: bool,
+    /// Static host to address mappings used instead of DNS
+    pub resolve: Vec<(String, SocketAddr)>,
+    /// Targets whose records are flushed immediately
+    pub flush_on_target: Vec<String>,
+    /// Sort lines in each batch by when they were logged
+    pub sort_batches: bool,
+    /// Called with the duration of each flush that sent logs
+    pub flush_latency_callback: Option<FlushLatencyCallback>,
+    /// Flush requests closer together than this are coalesced
+    pub min_flush_interval: Option<Duration>,
+    /// Flush on multiples of this interval since the unix epoch
+    pub aligned_flush: Option<Duration>,
+    /// Maximum bytes of log lines held by the writer before the oldest are evicted
+    pub max_buffer_bytes: Option<usize>,
+    /// Retries of the final flush when the writer shuts down
+    pub shutdown_retries: usize,
+    /// Most verbose level sent to DataDog
+    pub min_level: LevelFilter,
+    /// Proxy url requests to the api are sent through
+    pub proxy: Option<String>,
+    /// Format of each log line
+    pub log_format: LogFormat,
+    /// Trailing module path segments kept
+    pub module_path_depth: Option<usize>,
+    /// Prefix removed from module paths
+    pub module_path_strip_prefix: Option<String>,
+    /// Open a connection to the api when the writer starts
+    pub connection_warmup: bool,
+    /// Remove a single trailing newline from each line
+    pub trim_trailing_newline: bool,
+    /// Lines that waited longer than this in the channel are dropped
+    pub max_channel_dwell: Option<Duration>,
+    /// Dictionary used to zstd compress request bodies
+    #[cfg(feature = "zstd")]
+    pub zstd_dictionary: Option<Vec<u8>>,
+    /// Stream request bodies as they are encoded
+    #[cfg(feature = "stream")]
+    pub streaming_upload: bool,
+    /// Retries of a request that failed with a retryable error
+    pub max_retries: usize,
+    /// Delay before the first retry, doubled for each following retry
+    pub retry_base_delay: Duration,
+    /// Longest a Retry-After header may delay a retry
+    pub max_retry_after: Duration,
+    /// Format of request bodies
+    pub payload_format: PayloadFormat,
+    /// Function formatting records in place of the built in formats
+    pub formatter: Option<FormatFunction>,
+    /// Prepend text lines with the time they were logged
+    pub line_timestamp: bool,
+    /// Maximum lines held in the channel between the adapter and writer
+    pub channel_capacity: Option<usize>,
+    /// Behavior when the channel between the adapter and writer is full
+    pub overflow_policy: OverflowPolicy,
+    /// Longest a request to the api may take
+    pub request_timeout: Duration,
+    /// HTTP client used in place of one built from this configuration
+    pub client: Option<Client>,
+    /// Username and password sent to the proxy
+    pub proxy_credentials: Option<(String, String)>,
+    /// Longest a flush waits for the writer
+    pub flush_timeout: Option<Duration>,
+    /// Longest shutting down may take
+    pub shutdown_timeout: Option<Duration>,
+    /// DataDog statuses overriding the default for each level
+    pub status_mapping: Vec<(Level, String)>,
+    /// Function choosing the source of each record
+    pub source_mapping: Option<SourceMapping>,
+    /// Function extracting the trace and span ids of each record
+    pub trace_context: Option<TraceContext>,
+    /// Maximum size of a log line in bytes, always set by [`DataDogConfigBuilder::build`]
+    pub max_line_size: Option<usize>,
+    /// Behavior for lines over the maximum line size
+    pub oversized_line_policy: OversizedLinePolicy,
+    /// Callback receiving lines dropped for exceeding the maximum line size
+    pub on_dropped: Option<DroppedLineCallback>,
+    /// Gzip compression level
+    pub compression_level: u32,
+    /// Request body compression
+    pub compression: Compression,
+    /// File undelivered lines are appended to when a flush fails
+    pub fallback_file: Option<PathBuf>,
+    /// Consecutive failed sends that pause sending, and for how long
+    pub circuit_breaker: Option<(usize, Duration)>,
+    /// Callback receiving the writer's errors
+    pub on_error: Option<ErrorCallback>,
+    /// Attributes merged into every JSON log object
+    pub attributes: Map<String, Value>,
+    /// Function routing records of each level to another service or tags
+    pub level_routing: Option<LevelRouting>,
+    /// Initial flush is moved earlier by a random offset up to this
+    pub flush_jitter: Option<Duration>,
+    /// Called for the api key of each request, in place of the static key
+    pub api_key_provider: Option<ApiKeyProvider>,
+    /// Channel fill ratio at which backpressure is reported
+    pub high_water_mark: Option<f64>,
+    /// Header carrying the api key
+    pub api_key_header: String,
+    /// Value of the api key header, with `{api_key}` replaced by the api key
+    pub api_key_template: Option<String>,
+    /// Key-value whose numeric syslog severity sets the DataDog status
+    pub status_from_kv: Option<String>,
+    /// Precision of line timestamps
+    pub timestamp_precision: TimestampPrecision,
+    /// Add how long each line was buffered to JSON log objects
+    pub buffer_latency_attr: bool,
+    /// Connect to the TCP intake over TLS
+    #[cfg(feature = "tcp-tls")]
+    pub tcp_tls: bool,
 }
 
 /// Builder for [`DataDogConfig`]
@@ -48,8 +556,147 @@ pub struct DataDogConfigBuilder {
     source: String,
     /// Maximum log lines in a single request
     max_log_lines: Option<usize>,
-    /// Maximum allowed api request size
+    /// Uncompressed bytes of buffered log lines that trigger a flush
     max_payload_size: Option<usize>,
+    /// Whether to tag logs with os/arch/rustc metadata
+    host_metadata: bool,
+    /// Warn once if the api's clock differs from the local clock by more than this
+    clock_skew_warning: Option<Duration>,
+    /// Layout of text log lines
+    text_layout: TextLayout,
+    /// Gzip compression of request bodies
+    gzip: GzipMode,
+    /// Filter applied to records before they are sent
+    filter: Option<RecordFilter>,
+    /// Behavior when a log line cannot be sent to the writer
+    send_failure_policy: SendFailurePolicy,
+    /// Local file every line is also written to, and its maximum size before rotation
+    local_tee: Option<(PathBuf, usize)>,
+    /// Pick compression from the api's advertised `Accept-Encoding`
+    negotiated_compression: bool,
+    /// When to replace the HTTP client with a fresh one
+    client_recycle: Option<ClientRecycle>,
+    /// Maximum number of tags
+    max_tags: Option<usize>,
+    /// Resolve the OS hostname at build time when no hostname is set
+    auto_hostname: bool,
+    /// Fail [`Self::try_build`] on tags that need sanitizing
+    strict_tags: bool,
+    /// Stop sending logs after repeated authentication failures
+    auth_failure_shutdown: bool,
+    /// Static host to address mappings used instead of DNS
+    resolve: Vec<(String, SocketAddr)>,
+    /// Targets whose records are flushed immediately
+    flush_on_target: Vec<String>,
+    /// Sort lines in each batch by when they were logged
+    sort_batches: bool,
+    /// Called with the duration of each flush that sent logs
+    flush_latency_callback: Option<FlushLatencyCallback>,
+    /// Flush requests closer together than this are coalesced
+    min_flush_interval: Option<Duration>,
+    /// Flush on multiples of this interval since the unix epoch
+    aligned_flush: Option<Duration>,
+    /// Environment sent as the `env` tag
+    env: Option<String>,
+    /// Maximum bytes of log lines held by the writer before the oldest are evicted
+    max_buffer_bytes: Option<usize>,
+    /// Retries of the final flush when the writer shuts down
+    shutdown_retries: usize,
+    /// Most verbose level sent to DataDog
+    min_level: LevelFilter,
+    /// Proxy url requests to the api are sent through
+    proxy: Option<String>,
+    /// Format of each log line
+    log_format: LogFormat,
+    /// Trailing module path segments kept
+    module_path_depth: Option<usize>,
+    /// Prefix removed from module paths
+    module_path_strip_prefix: Option<String>,
+    /// Open a connection to the api when the writer starts
+    connection_warmup: bool,
+    /// Remove a single trailing newline from each line
+    trim_trailing_newline: bool,
+    /// Lines that waited longer than this in the channel are dropped
+    max_channel_dwell: Option<Duration>,
+    /// Dictionary used to zstd compress request bodies
+    #[cfg(feature = "zstd")]
+    zstd_dictionary: Option<Vec<u8>>,
+    /// Stream request bodies as they are encoded
+    #[cfg(feature = "stream")]
+    streaming_upload: bool,
+    /// Retries of a request that failed with a retryable error
+    max_retries: usize,
+    /// Delay before the first retry, doubled for each following retry
+    retry_base_delay: Duration,
+    /// Longest a Retry-After header may delay a retry
+    max_retry_after: Duration,
+    /// DataDog site logs are sent to
+    site: DataDogSite,
+    /// Format of request bodies
+    payload_format: PayloadFormat,
+    /// Function formatting records in place of the built in formats
+    formatter: Option<FormatFunction>,
+    /// Prepend text lines with the time they were logged
+    line_timestamp: bool,
+    /// Maximum lines held in the channel between the adapter and writer
+    channel_capacity: Option<usize>,
+    /// Behavior when the channel between the adapter and writer is full
+    overflow_policy: OverflowPolicy,
+    /// Longest a request to the api may take
+    request_timeout: Duration,
+    /// HTTP client used in place of one built from this configuration
+    client: Option<Client>,
+    /// Username and password sent to the proxy
+    proxy_credentials: Option<(String, String)>,
+    /// Longest a flush waits for the writer
+    flush_timeout: Option<Duration>,
+    /// Longest shutting down may take
+    shutdown_timeout: Option<Duration>,
+    /// DataDog statuses overriding the default for each level
+    status_mapping: Vec<(Level, String)>,
+    /// Function choosing the source of each record
+    source_mapping: Option<SourceMapping>,
+    /// Function extracting the trace and span ids of each record
+    trace_context: Option<TraceContext>,
+    /// Maximum size of a log line in bytes
+    max_line_size: Option<usize>,
+    /// Behavior for lines over the maximum line size
+    oversized_line_policy: OversizedLinePolicy,
+    /// Callback receiving lines dropped for exceeding the maximum line size
+    on_dropped: Option<DroppedLineCallback>,
+    /// Gzip compression level
+    compression_level: u32,
+    /// Request body compression
+    compression: Compression,
+    /// File undelivered lines are appended to when a flush fails
+    fallback_file: Option<PathBuf>,
+    /// Consecutive failed sends that pause sending, and for how long
+    circuit_breaker: Option<(usize, Duration)>,
+    /// Callback receiving the writer's errors
+    on_error: Option<ErrorCallback>,
+    /// Attributes merged into every JSON log object
+    attributes: Option<Value>,
+    /// Function routing records of each level to another service or tags
+    level_routing: Option<LevelRouting>,
+    /// Initial flush is moved earlier by a random offset up to this
+    flush_jitter: Option<Duration>,
+    /// Called for the api key of each request, in place of the static key
+    api_key_provider: Option<ApiKeyProvider>,
+    /// Channel fill ratio at which backpressure is reported
+    high_water_mark: Option<f64>,
+    /// Header carrying the api key
+    api_key_header: String,
+    /// Value of the api key header, with `{api_key}` replaced by the api key
+    api_key_template: Option<String>,
+    /// Key-value whose numeric syslog severity sets the DataDog status
+    status_from_kv: Option<String>,
+    /// Precision of line timestamps
+    timestamp_precision: TimestampPrecision,
+    /// Add how long each line was buffered to JSON log objects
+    buffer_latency_attr: bool,
+    /// Connect to the TCP intake over TLS
+    #[cfg(feature = "tcp-tls")]
+    tcp_tls: bool,
 }
 
 impl DataDogConfigBuilder {
@@ -61,10 +708,143 @@ impl DataDogConfigBuilder {
             api_key,
             api_host: None,
             tags: vec![],
-            source: "rust".to_string(),
+            source: DEFAULT_SOURCE.to_string(),
             max_log_lines: None,
             max_payload_size: None,
+            host_metadata: false,
+            clock_skew_warning: None,
+            text_layout: TextLayout::default(),
+            gzip: GzipMode::default(),
+            filter: None,
+            send_failure_policy: SendFailurePolicy::default(),
+            local_tee: None,
+            negotiated_compression: false,
+            client_recycle: None,
+            max_tags: None,
+            auto_hostname: false,
+            strict_tags: false,
+            auth_failure_shutdown: true,
+            resolve: vec![],
+            flush_on_target: vec![],
+            sort_batches: false,
+            flush_latency_callback: None,
+            min_flush_interval: None,
+            aligned_flush: None,
+            env: None,
+            max_buffer_bytes: None,
+            shutdown_retries: 0,
+            min_level: LevelFilter::Trace,
+            proxy: None,
+            log_format: LogFormat::default(),
+            module_path_depth: None,
+            module_path_strip_prefix: None,
+            connection_warmup: false,
+            trim_trailing_newline: true,
+            max_channel_dwell: None,
+            #[cfg(feature = "zstd")]
+            zstd_dictionary: None,
+            #[cfg(feature = "stream")]
+            streaming_upload: false,
+            max_retries: 0,
+            retry_base_delay: Duration::milliseconds(DEFAULT_RETRY_BASE_DELAY_MS),
+            max_retry_after: Duration::seconds(DEFAULT_MAX_RETRY_AFTER_SECS),
+            site: DataDogSite::default(),
+            payload_format: PayloadFormat::default(),
+            formatter: None,
+            line_timestamp: false,
+            channel_capacity: None,
+            overflow_policy: OverflowPolicy::default(),
+            request_timeout: Duration::seconds(DEFAULT_REQUEST_TIMEOUT_SECS),
+            client: None,
+            proxy_credentials: None,
+            flush_timeout: None,
+            shutdown_timeout: None,
+            status_mapping: vec![],
+            source_mapping: None,
+            trace_context: None,
+            max_line_size: None,
+            oversized_line_policy: OversizedLinePolicy::default(),
+            on_dropped: None,
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            compression: Compression::default(),
+            fallback_file: None,
+            circuit_breaker: None,
+            on_error: None,
+            attributes: None,
+            level_routing: None,
+            flush_jitter: None,
+            api_key_provider: None,
+            high_water_mark: Some(DEFAULT_HIGH_WATER_MARK),
+            api_key_header: DEFAULT_API_KEY_HEADER.to_string(),
+            api_key_template: None,
+            status_from_kv: None,
+            timestamp_precision: TimestampPrecision::default(),
+            buffer_latency_attr: false,
+            #[cfg(feature = "tcp-tls")]
+            tcp_tls: false,
+        }
+    }
+
+    /// Create new [`DataDogConfigBuilder`] configured the way the DataDog agent is, from the
+    /// `DD_API_KEY` variable and optionally `DD_SITE`, `DD_SERVICE` and `DD_HOSTNAME`.
+    ///
+    /// Without `DD_SERVICE` the executable name is used, and without `DD_HOSTNAME` the
+    /// `HOSTNAME` variable
+    pub fn from_env() -> Result<Self, Error> {
+        let service = env_var(DD_SERVICE_VAR)
+            .or_else(|| {
+                std::env::current_exe()
+                    .ok()
+                    .and_then(|p| p.file_stem().map(|s| s.to_string_lossy().to_string()))
+            })
+            .unwrap_or_else(|| "rust".to_string());
+        let hostname = env_var(DD_HOSTNAME_VAR)
+            .or_else(|| env_var("HOSTNAME"))
+            .unwrap_or_else(|| "unknown".to_string());
+        let mut builder = Self::new(hostname, service, String::new());
+        builder.api_key_from_env(DD_API_KEY_VAR)?;
+        if let Some(site) = env_var(DD_SITE_VAR) {
+            builder.with_api_host(Some(intake_url(&site)));
         }
+        Ok(builder)
+    }
+
+    /// Configure api key from the environment variable `var_name`, so it isn't embedded in
+    /// source
+    pub fn api_key_from_env(&mut self, var_name: &str) -> Result<&mut Self, Error> {
+        self.api_key = env_var(var_name).ok_or_else(|| MissingEnvVar(var_name.to_string()))?;
+        Ok(self)
+    }
+
+    /// Configure a function returning the api key, called for every request so the key can be
+    /// rotated while running, e.g. by reading it from an `ArcSwap` updated by a secrets
+    /// watcher. The static api key is then ignored
+    pub fn with_api_key_provider<F>(&mut self, provider: F) -> &mut Self
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.api_key_provider = Some(Arc::new(provider));
+        self
+    }
+
+    /// Configure the header the api key is sent in, for proxies expecting it under another
+    /// name. Defaults to [`DEFAULT_API_KEY_HEADER`]
+    pub fn with_api_key_header<T>(&mut self, name: T) -> &mut Self
+    where
+        String: From<T>,
+    {
+        self.api_key_header = name.into();
+        self
+    }
+
+    /// Configure the value of the api key header, with [`API_KEY_PLACEHOLDER`] replaced by the
+    /// api key, e.g. `"Bearer {api_key}"` with an `Authorization` header
+    pub fn with_api_key_template<T>(&mut self, template: T) -> &mut Self
+    where
+        String: From<T>,
+    {
+        self.api_key_template = Some(template.into());
+        self
     }
 
     /// Configure api uri
@@ -73,6 +853,13 @@ impl DataDogConfigBuilder {
         self
     }
 
+    /// Resolve the OS hostname when building, used when the hostname given to [`Self::new`] is
+    /// empty. Falls back to `"unknown"` with a warning if the OS hostname can't be read
+    pub fn with_auto_hostname(&mut self) -> &mut Self {
+        self.auto_hostname = true;
+        self
+    }
+
     /// Configure tags that will be applied to logs
     pub fn with_tags<S, T>(&mut self, tags: Vec<(S, T)>) -> &mut Self
     where
@@ -86,36 +873,753 @@ impl DataDogConfigBuilder {
         self
     }
 
-    /// Configure source
-    pub fn with_source(&mut self, source: String) -> &mut Self {
-        self.source = source;
+    /// Add a tag to those already configured, a key may be given several values
+    pub fn add_tag<S, T>(&mut self, key: S, value: T) -> &mut Self
+    where
+        String: From<S>,
+        String: From<T>,
+    {
+        self.tags.push((key.into(), value.into()));
+        self
+    }
+
+    /// Add a key-less tag, e.g. `canary`, to those already configured
+    pub fn add_bare_tag<T>(&mut self, value: T) -> &mut Self
+    where
+        String: From<T>,
+    {
+        self.tags.push((String::new(), value.into()));
+        self
+    }
+
+    /// Configure the `ddsource` DataDog pipelines use to pick how logs are processed. Defaults
+    /// to [`DEFAULT_SOURCE`], set it to e.g. `"python"` when sharing pipelines with services in
+    /// another language
+    pub fn with_source<T>(&mut self, source: T) -> &mut Self
+    where
+        String: From<T>,
+    {
+        self.source = source.into();
         self
     }
 
-    /// Configure max log lines
+    /// Configure the maximum log lines buffered before flushing and sent in a single request
     pub fn with_max_log_lines(&mut self, count: Option<usize>) -> &mut Self {
         self.max_log_lines = count;
         self
     }
 
-    /// Configure max payload size
+    /// Configure max payload size.
+    ///
+    /// This decides when the buffer is flushed. Once the uncompressed log lines reach it, a body
+    /// that would be compressed is test-compressed and the buffer keeps growing until the
+    /// compressed size would reach it, so compressible logs are sent in fewer, larger requests.
+    /// Each request body is compressed once as a whole, and the api's 5MB request limit is
+    /// applied to the final (compressed) body, splitting the flush into more requests if needed
     pub fn with_max_payload_size(&mut self, bytes: Option<usize>) -> &mut Self {
         self.max_payload_size = bytes;
         self
     }
 
-    /// Build [`DataDogConfig`]
+    /// Configure whether `os`, `arch` and `rustc` tags are added to logs
+    pub fn with_host_metadata(&mut self, enabled: bool) -> &mut Self {
+        self.host_metadata = enabled;
+        self
+    }
+
+    /// Configure clock skew warning threshold, compared against the api response `Date` header
+    pub fn with_clock_skew_warning(&mut self, threshold: Option<Duration>) -> &mut Self {
+        self.clock_skew_warning = threshold;
+        self
+    }
+
+    /// Configure layout of text log lines
+    pub fn with_text_layout(&mut self, layout: TextLayout) -> &mut Self {
+        self.text_layout = layout;
+        self
+    }
+
+    /// Configure gzip compression, decided per request based on the [`GzipMode`]. Selects
+    /// [`Compression::Gzip`]
+    pub fn with_gzip(&mut self, gzip: GzipMode) -> &mut Self {
+        self.compression = Compression::Gzip;
+        self.gzip = gzip;
+        self
+    }
+
+    /// Configure a filter to keep, drop or rewrite records.
+    ///
+    /// The filter runs on the logging thread in [`crate::adapter::DataDogAdapter`], so it must be cheap
+    pub fn with_filter<F>(&mut self, filter: F) -> &mut Self
+    where
+        F: Fn(&Record) -> FilterResult + Send + Sync + 'static,
+    {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Configure behavior when a log line cannot be sent to the writer
+    pub fn with_send_failure_policy(&mut self, policy: SendFailurePolicy) -> &mut Self {
+        self.send_failure_policy = policy;
+        self
+    }
+
+    /// Configure a local file that every line is also written to.
+    ///
+    /// When the file would exceed `max_bytes` it is moved to `<path>.1` and a new file is started
+    pub fn with_local_tee(&mut self, path: PathBuf, max_bytes: usize) -> &mut Self {
+        self.local_tee = Some((path, max_bytes));
+        self
+    }
+
+    /// Configure whether compression is picked from the encodings the api advertises.
+    ///
+    /// This costs one extra `OPTIONS` request to the api before the first send, after which the
+    /// result is cached for the life of the writer. If the request fails the configured
+    /// [`GzipMode`] is used and negotiation is retried on the next send
+    pub fn with_negotiated_compression(&mut self, enabled: bool) -> &mut Self {
+        self.negotiated_compression = enabled;
+        self
+    }
+
+    /// Configure when the HTTP client is replaced, giving a fresh connection pool and DNS lookups.
+    ///
+    /// Buffered logs are unaffected, the swap happens between requests
+    pub fn with_client_recycle(&mut self, recycle: Option<ClientRecycle>) -> &mut Self {
+        self.client_recycle = recycle;
+        self
+    }
+
+    /// Configure maximum number of tags.
+    ///
+    /// [`Self::build`] keeps the first `max` tags and warns, [`Self::try_build`] returns an error
+    pub fn with_max_tags(&mut self, max: Option<usize>) -> &mut Self {
+        self.max_tags = max;
+        self
+    }
+
+    /// Configure whether tags breaking DataDog's tag rules are an error.
+    ///
+    /// Tags are always sanitized, e.g. a comma in a value would otherwise split it in two. By
+    /// default they are sanitized with a warning, when strict [`Self::try_build`] returns an error
+    pub fn with_strict_tags(&mut self, strict: bool) -> &mut Self {
+        self.strict_tags = strict;
+        self
+    }
+
+    /// Configure whether the writer stops sending logs after repeated 401/403 responses.
+    ///
+    /// An invalid api key is never going to succeed, so once disabled buffered logs are dropped,
+    /// counted in [`crate::stats::WriterStats::dropped_lines`] and written to the fallback file
+    /// if one is configured
+    pub fn with_auth_failure_shutdown(&mut self, enabled: bool) -> &mut Self {
+        self.auth_failure_shutdown = enabled;
+        self
+    }
+
+    /// Resolve `host` to `addr` instead of using DNS.
+    ///
+    /// Applied when the HTTP client is constructed. The port of `addr` is ignored, the port from
+    /// the api url is used
+    pub fn with_resolve<S: Into<String>>(&mut self, host: S, addr: SocketAddr) -> &mut Self {
+        self.resolve.push((host.into(), addr));
+        self
+    }
+
+    /// Configure targets whose records trigger an immediate flush.
+    ///
+    /// A target matches itself and any module beneath it, e.g. `payments` matches `payments::charge`
+    pub fn with_flush_on_target<S: Into<String>>(&mut self, targets: Vec<S>) -> &mut Self {
+        self.flush_on_target = targets.into_iter().map(|t| t.into()).collect_vec();
+        self
+    }
+
+    /// Configure whether lines in each batch are sorted by when they were logged, rather than the
+    /// order they reached the writer
+    pub fn with_sort_batches(&mut self, enabled: bool) -> &mut Self {
+        self.sort_batches = enabled;
+        self
+    }
+
+    /// Configure a callback receiving how long each flush took, including serialization,
+    /// compression and all requests. Called whether or not the flush succeeded
+    pub fn with_flush_latency_callback<F>(&mut self, callback: F) -> &mut Self
+    where
+        F: Fn(std::time::Duration) + Send + Sync + 'static,
+    {
+        self.flush_latency_callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Configure minimum interval between flushes requested through the adapter.
+    ///
+    /// A flush request arriving sooner than this after the previous one waits for a single flush
+    /// performed once the interval has passed, and returns that flush's result
+    pub fn with_min_flush_interval(&mut self, interval: Option<Duration>) -> &mut Self {
+        self.min_flush_interval = interval;
+        self
+    }
+
+    /// Configure flushes aligned to wall clock boundaries, e.g. every 10 seconds on the 10 second
+    /// mark. When set this is used in place of the relative flush interval
+    pub fn with_aligned_flush(&mut self, interval: Option<Duration>) -> &mut Self {
+        self.aligned_flush = interval;
+        self
+    }
+
+    /// Configure a random offset, up to `jitter`, by which the first interval flush is moved
+    /// earlier, so instances started together don't keep flushing at the same moment
+    pub fn with_flush_jitter(&mut self, jitter: Option<Duration>) -> &mut Self {
+        self.flush_jitter = jitter;
+        self
+    }
+
+    /// Configure the environment sent as the `env` tag
+    pub fn with_env(&mut self, env: String) -> &mut Self {
+        self.env = Some(env);
+        self
+    }
+
+    /// Configure the environment sent as the `env` tag from the `DD_ENV` variable, warning if it
+    /// is unset or empty
+    pub fn with_env_from_var(&mut self) -> &mut Self {
+        match std::env::var(DD_ENV_VAR) {
+            Ok(env) if !env.trim().is_empty() => self.env = Some(env),
+            _ => warn!(
+                "{} is unset or empty, logs will not have an env tag",
+                DD_ENV_VAR
+            ),
+        }
+        self
+    }
+
+    /// Configure the maximum bytes of log lines held by the writer. When flushes fail and the
+    /// buffer grows past this, the oldest lines are evicted and counted in
+    /// [`crate::stats::WriterStats`]. Should be larger than the maximum payload size
+    pub fn with_max_buffer_bytes(&mut self, bytes: Option<usize>) -> &mut Self {
+        self.max_buffer_bytes = bytes;
+        self
+    }
+
+    /// Configure how many times the final flush when the writer shuts down is retried before
+    /// the remaining lines are dropped
+    pub fn with_shutdown_retries(&mut self, retries: usize) -> &mut Self {
+        self.shutdown_retries = retries;
+        self
+    }
+
+    /// Configure the most verbose level sent to DataDog. Can be changed while running with
+    /// [`crate::adapter::DataDogAdapter::set_min_level`]
+    pub fn with_min_level(&mut self, level: LevelFilter) -> &mut Self {
+        self.min_level = level;
+        self
+    }
+
+    /// Configure a proxy url all requests to the api are sent through
+    pub fn with_proxy(&mut self, proxy: Option<String>) -> &mut Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Configure the format of each log line
+    pub fn with_log_format(&mut self, format: LogFormat) -> &mut Self {
+        self.log_format = format;
+        self
+    }
+
+    /// Configure how many trailing module path segments are kept, e.g. `Some(1)` logs only the
+    /// leaf module. Paths with fewer segments are kept whole
+    pub fn with_module_path_depth(&mut self, depth: Option<usize>) -> &mut Self {
+        self.module_path_depth = depth;
+        self
+    }
+
+    /// Configure a prefix removed from module paths, e.g. `my_service` logs
+    /// `my_service::infra::db` as `infra::db`. Applied before [`Self::with_module_path_depth`]
+    pub fn with_module_path_strip_prefix(&mut self, prefix: Option<String>) -> &mut Self {
+        self.module_path_strip_prefix = prefix;
+        self
+    }
+
+    /// Configure whether the writer opens a connection to the api when it starts, so the first
+    /// flush doesn't pay for the TLS handshake
+    pub fn with_connection_warmup(&mut self, enabled: bool) -> &mut Self {
+        self.connection_warmup = enabled;
+        self
+    }
+
+    /// Configure whether a single trailing newline is removed from each line, since the
+    /// newline delimited request body would otherwise contain blank lines
+    pub fn with_trim_trailing_newline(&mut self, enabled: bool) -> &mut Self {
+        self.trim_trailing_newline = enabled;
+        self
+    }
+
+    /// Configure the longest a line may wait in the channel between the adapter and writer.
+    /// Older lines are dropped as stale and counted in [`crate::stats::WriterStats`]
+    pub fn with_max_channel_dwell(&mut self, max: Option<Duration>) -> &mut Self {
+        self.max_channel_dwell = max;
+        self
+    }
+
+    /// Configure zstd compression of request bodies with a pre-shared dictionary, used in place
+    /// of the configured [`Compression`]. DataDog's intake has no way to share the dictionary, so
+    /// this requires a gateway in front of it that holds the same dictionary and decompresses
+    /// requests
+    #[cfg(feature = "zstd")]
+    pub fn with_zstd_dictionary(&mut self, dictionary: Vec<u8>) -> &mut Self {
+        self.zstd_dictionary = Some(dictionary);
+        self
+    }
+
+    /// Configure streaming request bodies: each body is serialized and gzipped a chunk of lines
+    /// at a time while it uploads, rather than encoded in memory first, bounding the memory a
+    /// large flush uses. Since the compressed size isn't known until the body has been sent,
+    /// bodies are split to fit the request limit by their uncompressed size. OTLP payloads and
+    /// zstd compression are always buffered
+    #[cfg(feature = "stream")]
+    pub fn with_streaming_upload(&mut self, streaming: bool) -> &mut Self {
+        self.streaming_upload = streaming;
+        self
+    }
+
+    /// Configure how many times a request that failed with a transport error, 429 or 5xx is
+    /// retried before the flush fails and its lines are kept for the next flush, or written to
+    /// the fallback file if one is configured
+    pub fn with_max_retries(&mut self, retries: usize) -> &mut Self {
+        self.max_retries = retries;
+        self
+    }
+
+    /// Configure the delay before the first retry, doubled for each following retry with up to
+    /// 50% random jitter added
+    pub fn with_retry_base_delay(&mut self, delay: Duration) -> &mut Self {
+        self.retry_base_delay = delay;
+        self
+    }
+
+    /// Configure the longest a `Retry-After` header on a 429 response may delay a retry
+    pub fn with_max_retry_after(&mut self, max: Duration) -> &mut Self {
+        self.max_retry_after = max;
+        self
+    }
+
+    /// Configure the DataDog site logs are sent to. An api host set with
+    /// [`Self::with_api_host`] takes precedence
+    pub fn with_site(&mut self, site: DataDogSite) -> &mut Self {
+        self.site = site;
+        self
+    }
+
+    /// Configure the format of request bodies
+    pub fn with_payload_format(&mut self, format: PayloadFormat) -> &mut Self {
+        self.payload_format = format;
+        self
+    }
+
+    /// Configure a function formatting each record in place of [`LogFormat`] and [`TextLayout`],
+    /// like flexi_logger's own format functions.
+    ///
+    /// The function runs on the logging thread in [`crate::adapter::DataDogAdapter`], so it must
+    /// be cheap
+    pub fn with_format<F>(&mut self, format: F) -> &mut Self
+    where
+        F: Fn(&mut DeferredNow, &Record) -> String + Send + Sync + 'static,
+    {
+        self.formatter = Some(Arc::new(format));
+        self
+    }
+
+    /// Configure prepending each text line with the RFC 3339 time it was logged, so DataDog
+    /// can use the event time rather than the ingestion time. JSON lines and JSON payloads
+    /// always carry the event time in their `date` field
+    pub fn with_line_timestamp(&mut self, line_timestamp: bool) -> &mut Self {
+        self.line_timestamp = line_timestamp;
+        self
+    }
+
+    /// Configure a bounded channel between the adapter and writer holding at most `capacity`
+    /// lines, [`None`] for an unbounded channel.
+    ///
+    /// An unbounded channel never blocks or drops lines, but grows without limit while the writer
+    /// can't keep up, e.g. during a burst of logging while DataDog is unreachable. A bounded
+    /// channel caps that memory, applying the [`OverflowPolicy`] when full
+    pub fn with_channel_capacity(&mut self, capacity: Option<usize>) -> &mut Self {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    /// Configure what happens when a bounded channel is full, see [`OverflowPolicy`]
+    pub fn with_overflow_policy(&mut self, policy: OverflowPolicy) -> &mut Self {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Configure the fill ratio of a bounded channel, from 0 to 1, at which a warning is logged
+    /// and [`crate::stats::WriterStats::high_water_crossings`] is counted, once each time the
+    /// channel fills past it. [`None`] disables the warning, the current ratio is still readable
+    /// from [`crate::adapter::DataDogAdapter::pressure`]. Defaults to 0.8
+    pub fn with_high_water_mark(&mut self, ratio: Option<f64>) -> &mut Self {
+        self.high_water_mark = ratio;
+        self
+    }
+
+    /// Configure how long a request to the api may take before it fails, including connecting
+    /// and reading the response, or connecting and writing to the TCP intake. Defaults to 10
+    /// seconds, so a hung connection can't stall the writer indefinitely
+    pub fn with_request_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Configure the HTTP client used to send logs, e.g. to share an application's connection
+    /// pool, root certificates or DNS overrides. [`Self::with_resolve`], [`Self::with_proxy`],
+    /// [`Self::with_request_timeout`] and [`Self::with_client_recycle`] don't apply to it, they
+    /// must be configured on the client itself
+    pub fn with_client(&mut self, client: Client) -> &mut Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Configure the username and password sent to the proxy configured with
+    /// [`Self::with_proxy`], using basic authentication
+    pub fn with_proxy_credentials(&mut self, credentials: Option<(String, String)>) -> &mut Self {
+        self.proxy_credentials = credentials;
+        self
+    }
+
+    /// Configure how long [`crate::adapter::DataDogAdapter`]'s flush waits for the writer before
+    /// failing with [`std::io::ErrorKind::TimedOut`], [`None`] to wait indefinitely. The writer
+    /// still sends the lines, only the caller stops waiting
+    pub fn with_flush_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.flush_timeout = timeout;
+        self
+    }
+
+    /// Configure how long shutting down may take, [`None`] to wait indefinitely. The adapter
+    /// stops waiting for its final flush after this long, and the writer abandons its final flush
+    /// once it has passed, even mid-retry, moving the lines to the fallback file if there is one
+    /// and otherwise logging how many were lost
+    pub fn with_shutdown_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Configure the DataDog `status` sent for records of `level` in
+    /// [`PayloadFormat::Json`] payloads, e.g. `debug` for [`Level::Trace`]. By default each level
+    /// maps to its lowercase name
+    pub fn with_status_mapping<S: Into<String>>(&mut self, level: Level, status: S) -> &mut Self {
+        self.status_mapping.retain(|(l, _)| *l != level);
+        self.status_mapping.push((level, status.into()));
+        self
+    }
+
+    /// Configure a key-value carrying a numeric syslog severity, 0 (`emerg`) to 7 (`debug`), that
+    /// sets the DataDog `status` of [`PayloadFormat::Json`] payloads in place of the record's
+    /// level, e.g. `syslog_severity` for logs bridged from syslog. Invalid or out of range values
+    /// fall back to the level's status with a warning
+    pub fn with_status_from_kv<S: Into<String>>(&mut self, key: S) -> &mut Self {
+        self.status_from_kv = Some(key.into());
+        self
+    }
+
+    /// Configure a function choosing the `ddsource` of each record, e.g. from its module path, to
+    /// route records to different DataDog pipelines. Records it returns [`None`] for use the
+    /// configured source.
+    ///
+    /// Text payloads carry the source in the request query, so lines are grouped into a request
+    /// per source, keeping their order within each source. The function runs on the logging
+    /// thread in [`crate::adapter::DataDogAdapter`], so it must be cheap
+    pub fn with_source_mapping<F>(&mut self, mapping: F) -> &mut Self
+    where
+        F: Fn(&Record) -> Option<String> + Send + Sync + 'static,
+    {
+        self.source_mapping = Some(Arc::new(mapping));
+        self
+    }
+
+    /// Configure a function extracting the DataDog APM trace and span ids of each record, e.g.
+    /// from the current `tracing` span or from key-values, attached as `dd.trace_id` and
+    /// `dd.span_id` so DataDog links logs to their traces. Key-values already named
+    /// `dd.trace_id` and `dd.span_id` are sent as is without this.
+    ///
+    /// The function runs on the logging thread in [`crate::adapter::DataDogAdapter`], so it must
+    /// be cheap
+    pub fn with_trace_context<F>(&mut self, trace_context: F) -> &mut Self
+    where
+        F: Fn(&Record) -> Option<TraceIds> + Send + Sync + 'static,
+    {
+        self.trace_context = Some(Arc::new(trace_context));
+        self
+    }
+
+    /// Configure the precision of line timestamps: the numeric `date` of
+    /// [`PayloadFormat::Json`] log objects, in units since the unix epoch, and the RFC 3339 time
+    /// of [`LogFormat::JsonLines`] and [`Self::with_line_timestamp`]. Milliseconds by default,
+    /// which is what DataDog expects
+    pub fn with_timestamp_precision(&mut self, precision: TimestampPrecision) -> &mut Self {
+        self.timestamp_precision = precision;
+        self
+    }
+
+    /// Configure adding a `buffered_ms` attribute to [`PayloadFormat::Json`] log objects: the
+    /// milliseconds from when the line was logged until its request body was built, showing how
+    /// long it spent buffered before being sent. Off by default
+    pub fn with_buffer_latency_attr(&mut self, enabled: bool) -> &mut Self {
+        self.buffer_latency_attr = enabled;
+        self
+    }
+
+    /// Configure connecting to the TCP intake over TLS, verifying it against the Mozilla root
+    /// certificates. Use the intake's TLS port, e.g. `intake.logs.datadoghq.com:10516`. Off by
+    /// default
+    #[cfg(feature = "tcp-tls")]
+    pub fn with_tcp_tls(&mut self, enabled: bool) -> &mut Self {
+        self.tcp_tls = enabled;
+        self
+    }
+
+    /// Configure the maximum log lines per request, line size and payload size together, e.g.
+    /// [`DataDogLimits::conservative`].
+    ///
+    /// [`Self::build`] warns if the limits are inconsistent, [`Self::try_build`] returns an error,
+    /// whether they were set here or with the separate methods
+    pub fn with_limits(&mut self, limits: DataDogLimits) -> &mut Self {
+        self.max_log_lines = Some(limits.max_log_lines);
+        self.max_line_size = Some(limits.max_line_size);
+        self.max_payload_size = Some(limits.max_payload_size);
+        self
+    }
+
+    /// Configure the maximum size of a log line in bytes, beyond which lines are dropped or
+    /// truncated according to [`Self::with_oversized_line_policy`]. [`None`] for the smaller of
+    /// the max payload size and DataDog's 1MB line limit. Lines over the request limit are always
+    /// dropped
+    pub fn with_max_line_size(&mut self, max_line_size: Option<usize>) -> &mut Self {
+        self.max_line_size = max_line_size;
+        self
+    }
+
+    /// Configure what happens to lines over the maximum line size
+    pub fn with_oversized_line_policy(&mut self, policy: OversizedLinePolicy) -> &mut Self {
+        self.oversized_line_policy = policy;
+        self
+    }
+
+    /// Configure a callback receiving each line dropped for exceeding the maximum line size
+    pub fn with_on_dropped<F>(&mut self, callback: F) -> &mut Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.on_dropped = Some(Arc::new(callback));
+        self
+    }
+
+    /// Configure the gzip compression level, from 0 (no compression, fastest) to 9 (smallest,
+    /// slowest), 6 by default.
+    ///
+    /// [`Self::build`] caps higher levels at 9 and warns, [`Self::try_build`] returns an error
+    pub fn with_compression_level(&mut self, level: u32) -> &mut Self {
+        self.compression_level = level;
+        self
+    }
+
+    /// Configure how request bodies are compressed. Gzip follows the [`GzipMode`] set with
+    /// [`Self::with_gzip`]
+    pub fn with_compression(&mut self, compression: Compression) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Configure a file that lines are appended to, one per line, when a flush fails after
+    /// exhausting its retries. Those lines are removed from the buffer rather than kept for the
+    /// next flush, and can be sent once DataDog is reachable again with [`crate::replay_file`]
+    pub fn with_fallback_file(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.fallback_file = path;
+        self
+    }
+
+    /// Configure a circuit breaker: after `threshold` consecutive failed sends, sending is paused
+    /// for `cooldown` while lines keep buffering up to the maximum buffer size, and flushes fail
+    /// with [`crate::error::Error::CircuitOpen`]. After the cooldown a single request without
+    /// retries probes the api, closing the circuit if it succeeds or pausing again if it fails
+    pub fn with_circuit_breaker(&mut self, threshold: usize, cooldown: Duration) -> &mut Self {
+        self.circuit_breaker = Some((threshold, cooldown));
+        self
+    }
+
+    /// Configure a callback receiving the writer's errors, e.g. failed sends, so the application
+    /// can react to them. Errors are still logged at error level and to stderr
+    pub fn with_on_error<F>(&mut self, callback: F) -> &mut Self
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        self.on_error = Some(Arc::new(callback));
+        self
+    }
+
+    /// Configure attributes deep-merged into every log object of JSON payloads, e.g.
+    /// `json!({"region": "us-east-1", "build": {"version": "1.2.3"}})`, which DataDog indexes as
+    /// facets. A record's own key-values take precedence over these where they conflict, and
+    /// `hostname`, `service`, `ddsource` and `ddtags` always come from their own settings.
+    ///
+    /// Must be a JSON object, [`Self::build`] ignores anything else and warns, [`Self::try_build`]
+    /// returns an error
+    pub fn with_attributes(&mut self, attributes: Value) -> &mut Self {
+        self.attributes = Some(attributes);
+        self
+    }
+
+    /// Configure a function routing records of a level to another service or adding tags, e.g.
+    /// sending errors to an alerting service tagged `severity:high`. Text payloads carry the
+    /// service and tags in the query, so lines are grouped into a request per route, keeping
+    /// their order within each route
+    pub fn with_level_routing<F>(&mut self, routing: F) -> &mut Self
+    where
+        F: Fn(Level) -> Option<LevelRoute> + Send + Sync + 'static,
+    {
+        self.level_routing = Some(Arc::new(routing));
+        self
+    }
+
+    /// Tags computed once at build time
+    fn build_tags(&self) -> Vec<(String, String)> {
+        let mut tags = self.tags.to_owned();
+        if self.host_metadata {
+            tags.extend([
+                ("os".to_string(), std::env::consts::OS.to_string()),
+                ("arch".to_string(), std::env::consts::ARCH.to_string()),
+                ("rustc".to_string(), RUSTC_VERSION.to_string()),
+            ]);
+        }
+        if let Some(env) = &self.env {
+            tags.retain(|(k, _)| k != "env");
+            tags.push(("env".to_string(), env.to_string()));
+        }
+        tags
+    }
+
+    /// The size limits in effect. An unconfigured line size is within the payload size
+    fn limits(&self) -> DataDogLimits {
+        let max_payload_size = self.max_payload_size.unwrap_or(DEFAULT_BODY_SEND_BYTES);
+        DataDogLimits {
+            max_log_lines: self.max_log_lines.unwrap_or(DEFAULT_MAX_LOG_LINES),
+            max_line_size: self
+                .max_line_size
+                .unwrap_or(max_payload_size.min(MAX_LINE_BYTES)),
+            max_payload_size,
+        }
+    }
+
+    /// Sanitize tags, returning the tags that had to be changed separately
+    fn sanitize_tags(tags: Vec<(String, String)>) -> (Vec<(String, String)>, Vec<String>) {
+        let mut invalid = vec![];
+        let tags = tags
+            .into_iter()
+            .map(|(key, value)| {
+                let sanitized = sanitize_tag(&key, &value);
+                if sanitized.0 != key || sanitized.1 != value {
+                    invalid.push(format_tag(&key, &value));
+                }
+                sanitized
+            })
+            .collect();
+        (tags, invalid)
+    }
+
+    /// Warn about tags that were sanitized
+    fn warn_sanitized(invalid: &[String]) {
+        for tag in invalid {
+            warn!("DataDog tag `{}` is invalid and was sanitized", tag);
+        }
+    }
+
+    /// Build [`DataDogConfig`], sanitizing invalid tags and truncating tags beyond the configured
+    /// maximum
     pub fn build(&self) -> DataDogConfig {
+        let (mut tags, invalid) = Self::sanitize_tags(self.build_tags());
+        Self::warn_sanitized(&invalid);
+        if self.compression_level > MAX_COMPRESSION_LEVEL {
+            warn!(
+                "Compression level {} is out of range, using {}",
+                self.compression_level, MAX_COMPRESSION_LEVEL
+            );
+        }
+        if self.attributes.as_ref().is_some_and(|a| !a.is_object()) {
+            warn!("Attributes must be a JSON object, ignoring them");
+        }
+        if let Err(e) = self.limits().validate() {
+            warn!("{}", e);
+        }
+        if let Some(max) = self.max_tags {
+            if tags.len() > max {
+                warn!(
+                    "{} DataDog tags configured, only the first {} will be sent",
+                    tags.len(),
+                    max
+                );
+                tags.truncate(max);
+            }
+        }
+        self.build_with_tags(tags)
+    }
+
+    /// Build [`DataDogConfig`], failing if the configuration is invalid, including invalid tags
+    /// when [`Self::with_strict_tags`] is set
+    pub fn try_build(&self) -> Result<DataDogConfig, Error> {
+        let (tags, invalid) = Self::sanitize_tags(self.build_tags());
+        if self.strict_tags && !invalid.is_empty() {
+            return Err(ConfigError(format!("Invalid tags {}", invalid.join(", "))));
+        }
+        Self::warn_sanitized(&invalid);
+        if self.compression_level > MAX_COMPRESSION_LEVEL {
+            return Err(ConfigError(format!(
+                "Compression level {} exceeds the maximum of {}",
+                self.compression_level, MAX_COMPRESSION_LEVEL
+            )));
+        }
+        self.limits().validate()?;
+        if let Err(e) = HeaderName::from_bytes(self.api_key_header.as_bytes()) {
+            return Err(ConfigError(format!(
+                "Invalid api key header {}: {}",
+                self.api_key_header, e
+            )));
+        }
+        if let Some(attributes) = self.attributes.as_ref().filter(|a| !a.is_object()) {
+            return Err(ConfigError(format!(
+                "Attributes must be a JSON object, not {}",
+                attributes
+            )));
+        }
+        if let Some(max) = self.max_tags {
+            if tags.len() > max {
+                return Err(ConfigError(format!(
+                    "{} tags exceeds the maximum of {}",
+                    tags.len(),
+                    max
+                )));
+            }
+        }
+        Ok(self.build_with_tags(tags))
+    }
+
+    /// Build [`DataDogConfig`] with the final set of tags
+    fn build_with_tags(&self, tags: Vec<(String, String)>) -> DataDogConfig {
+        let hostname = if self.auto_hostname && self.hostname.trim().is_empty() {
+            os_hostname()
+        } else {
+            self.hostname.to_owned()
+        };
         DataDogConfig {
-            hostname: self.hostname.to_owned(),
+            hostname,
             service: self.service.to_owned(),
             api_key: self.api_key.to_owned(),
             api_host: self
                 .api_host
                 .as_ref()
                 .map(|s| s.to_owned())
-                .unwrap_or_else(|| DEFAULT_DATADOG_INGEST_URL.to_string()),
-            tags: self.tags.to_owned(),
+                .unwrap_or_else(|| self.site.intake_url()),
+            tags,
             source: self.source.to_owned(),
             max_log_lines: self
                 .max_log_lines
@@ -127,6 +1631,74 @@ impl DataDogConfigBuilder {
                 .as_ref()
                 .map(|s| s.to_owned())
                 .unwrap_or(DEFAULT_BODY_SEND_BYTES),
+            clock_skew_warning: self.clock_skew_warning,
+            text_layout: self.text_layout,
+            gzip: self.gzip,
+            filter: self.filter.clone(),
+            send_failure_policy: self.send_failure_policy,
+            local_tee: self.local_tee.clone(),
+            negotiated_compression: self.negotiated_compression,
+            client_recycle: self.client_recycle,
+            auth_failure_shutdown: self.auth_failure_shutdown,
+            resolve: self.resolve.clone(),
+            flush_on_target: self.flush_on_target.clone(),
+            sort_batches: self.sort_batches,
+            flush_latency_callback: self.flush_latency_callback.clone(),
+            min_flush_interval: self.min_flush_interval,
+            aligned_flush: self.aligned_flush,
+            max_buffer_bytes: self.max_buffer_bytes,
+            shutdown_retries: self.shutdown_retries,
+            min_level: self.min_level,
+            proxy: self.proxy.clone(),
+            log_format: self.log_format,
+            module_path_depth: self.module_path_depth,
+            module_path_strip_prefix: self.module_path_strip_prefix.clone(),
+            connection_warmup: self.connection_warmup,
+            trim_trailing_newline: self.trim_trailing_newline,
+            max_channel_dwell: self.max_channel_dwell,
+            #[cfg(feature = "zstd")]
+            zstd_dictionary: self.zstd_dictionary.clone(),
+            #[cfg(feature = "stream")]
+            streaming_upload: self.streaming_upload,
+            max_retries: self.max_retries,
+            retry_base_delay: self.retry_base_delay,
+            max_retry_after: self.max_retry_after,
+            payload_format: self.payload_format,
+            formatter: self.formatter.clone(),
+            line_timestamp: self.line_timestamp,
+            channel_capacity: self.channel_capacity,
+            overflow_policy: self.overflow_policy,
+            request_timeout: self.request_timeout,
+            client: self.client.clone(),
+            proxy_credentials: self.proxy_credentials.clone(),
+            flush_timeout: self.flush_timeout,
+            shutdown_timeout: self.shutdown_timeout,
+            status_mapping: self.status_mapping.clone(),
+            source_mapping: self.source_mapping.clone(),
+            trace_context: self.trace_context.clone(),
+            max_line_size: Some(self.limits().max_line_size),
+            oversized_line_policy: self.oversized_line_policy,
+            on_dropped: self.on_dropped.clone(),
+            compression_level: self.compression_level.min(MAX_COMPRESSION_LEVEL),
+            compression: self.compression,
+            fallback_file: self.fallback_file.clone(),
+            circuit_breaker: self.circuit_breaker,
+            on_error: self.on_error.clone(),
+            attributes: match &self.attributes {
+                Some(Value::Object(attributes)) => attributes.clone(),
+                _ => Map::new(),
+            },
+            level_routing: self.level_routing.clone(),
+            flush_jitter: self.flush_jitter,
+            api_key_provider: self.api_key_provider.clone(),
+            high_water_mark: self.high_water_mark,
+            api_key_header: self.api_key_header.to_owned(),
+            api_key_template: self.api_key_template.clone(),
+            status_from_kv: self.status_from_kv.clone(),
+            timestamp_precision: self.timestamp_precision,
+            buffer_latency_attr: self.buffer_latency_attr,
+            #[cfg(feature = "tcp-tls")]
+            tcp_tls: self.tcp_tls,
         }
     }
 }
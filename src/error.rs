@@ -1,5 +1,6 @@
 //! Errors
 
+use flexi_logger::FlexiLoggerError;
 use log::error;
 use std::fmt::Debug;
 use std::io;
@@ -11,9 +12,20 @@ pub enum Error {
     /// Error in HTTP communication
     #[error("Http Error")]
     HttpError(#[from] reqwest::Error),
+    /// DataDog rejected a request, with the response body explaining why
+    #[error("DataDog api responded {status}: `{body}`")]
+    ApiError {
+        /// HTTP status code
+        status: u16,
+        /// Response body, usually a JSON error
+        body: String,
+    },
     /// IO Error
     #[error("IO Error")]
     IOError(#[from] io::Error),
+    /// The logger could not be set up
+    #[error("Logger Error")]
+    LoggerError(#[from] FlexiLoggerError),
     /// Error acquiring internal lock
     #[error("Lock Error")]
     LockError(String),
@@ -23,6 +35,28 @@ pub enum Error {
     /// Internal channel communication error
     #[error("Channel communication error: `{0}`")]
     ChannelError(String),
+    /// Log lines could not be flushed
+    #[error("Flush failed: `{0}`")]
+    FlushError(String),
+    /// Writer didn't finish flushing within the timeout
+    #[error("Timed out waiting for the writer to flush")]
+    FlushTimeout,
+    /// Writer has stopped, so nothing can be flushed
+    #[error("Writer is gone")]
+    WriterGone,
+    /// Sending is paused by the circuit breaker after repeated failures
+    #[error("Circuit open, not sending to DataDog until the cooldown elapses")]
+    CircuitOpen,
+    /// Invalid configuration
+    #[error("Invalid configuration: `{0}`")]
+    ConfigError(String),
+    /// Required environment variable is unset or empty
+    #[error("Missing environment variable: `{0}`")]
+    MissingEnvVar(String),
+    /// Writer task panicked or was cancelled
+    #[cfg(feature = "tokio-rt")]
+    #[error("Writer task failed")]
+    TaskError(#[from] tokio::task::JoinError),
 }
 
 /// Log error to stderr and at error level
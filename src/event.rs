@@ -0,0 +1,98 @@
+//! Log events passed from the adapter to the writer
+
+use crate::config::{format_tag, LevelRoute};
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use log::Level;
+use serde_json::{Map, Value};
+
+/// A formatted log line and the record details the writer needs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEvent {
+    /// Formatted log line
+    pub line: String,
+    /// Target of the record
+    pub target: String,
+    /// When the record was logged
+    pub timestamp: DateTime<Utc>,
+    /// Structured key-values of the record, sent as top-level attributes of JSON payloads
+    pub attributes: Map<String, Value>,
+    /// Source overriding the configured `ddsource`
+    pub source: Option<String>,
+    /// Service overriding the configured service
+    pub service: Option<String>,
+    /// Comma separated tags added to the configured tags
+    pub tags: Option<String>,
+    /// Level of the record, [`None`] for lines that didn't come from a record
+    pub level: Option<Level>,
+}
+
+impl LogEvent {
+    /// Create new [`LogEvent`]
+    pub fn new(line: String, target: String, timestamp: DateTime<Utc>) -> Self {
+        Self {
+            line,
+            target,
+            timestamp,
+            attributes: Map::new(),
+            source: None,
+            service: None,
+            tags: None,
+            level: None,
+        }
+    }
+
+    /// Set the level of the record
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Override the configured `ddsource`
+    pub fn with_source(mut self, source: Option<String>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Override the configured service and add to the configured tags
+    pub fn with_route(mut self, route: Option<LevelRoute>) -> Self {
+        if let Some(route) = route {
+            self.service = route.service;
+            self.tags = Some(route.tags.iter().map(|(k, v)| format_tag(k, v)).join(","))
+                .filter(|tags| !tags.is_empty());
+        }
+        self
+    }
+
+    /// Source, service and tags overrides, which lines must share to be sent in one text request
+    pub(crate) fn route_key(&self) -> (Option<&str>, Option<&str>, Option<&str>) {
+        (
+            self.source.as_deref(),
+            self.service.as_deref(),
+            self.tags.as_deref(),
+        )
+    }
+
+    /// Attach structured key-values
+    pub fn with_attributes(mut self, attributes: Map<String, Value>) -> Self {
+        self.attributes = attributes;
+        self
+    }
+}
+
+/// Channel carrying events from the adapter to the writer, bounded to `capacity` events if given
+pub(crate) fn channel(
+    capacity: Option<usize>,
+) -> (flume::Sender<LogEvent>, flume::Receiver<LogEvent>) {
+    match capacity {
+        Some(capacity) => flume::bounded(capacity),
+        None => flume::unbounded(),
+    }
+}
+
+/// Whether `target` is `prefix` or a module beneath it
+pub(crate) fn target_matches(target: &str, prefix: &str) -> bool {
+    target
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
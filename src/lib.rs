@@ -5,15 +5,38 @@
 
 use crate::adapter::DataDogAdapter;
 use crate::config::DataDogConfig;
-use crate::writer::DataDogHttpWriter;
+use crate::error::Error;
+#[cfg(feature = "tokio-rt")]
+use crate::logger::DataDogLogger;
+use crate::stats::WriterStats;
+#[cfg(feature = "tcp")]
+use crate::tcp::DataDogTcpWriter;
+use crate::writer::{DataDogHttpWriter, WriterChannels};
 use chrono::Duration;
-use flexi_logger::{FlexiLoggerError, Logger, LoggerHandle};
+use flexi_logger::{Logger, LoggerHandle};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+#[cfg(feature = "thread-rt")]
+use std::thread;
 #[cfg(feature = "tokio-rt")]
 use tokio::task::JoinHandle;
 
 pub mod adapter;
 pub mod config;
 pub mod error;
+pub mod event;
+#[cfg(feature = "tokio-rt")]
+pub mod logger;
+mod otlp;
+#[cfg(feature = "record")]
+pub mod recorder;
+mod rt;
+pub mod stats;
+#[cfg(feature = "tcp")]
+pub mod tcp;
+mod tee;
 pub mod writer;
 
 /// Create and set logger with the writer running on the tokio runtime
@@ -21,12 +44,51 @@ pub mod writer;
 pub async fn init_tokio_logger(
     datadog_config: DataDogConfig,
     flush_interval: Option<Duration>,
-) -> Result<(LoggerHandle, JoinHandle<()>), FlexiLoggerError> {
-    let (adapter, handle) = spawn_tokio_logger(datadog_config, flush_interval).await;
-    Logger::try_with_env()?
+) -> Result<(LoggerHandle, JoinHandle<()>), Error> {
+    init_tokio_logger_with(Logger::try_with_env()?, datadog_config, flush_interval).await
+}
+
+/// Create and set logger from a pre-configured [`Logger`], e.g. with its own
+/// [`flexi_logger::LogSpecification`], with the writer running on the tokio runtime.
+///
+/// DataDog becomes the logger's output, so other output has to be duplicated from it, e.g.
+/// `Logger::try_with_str("info")?.duplicate_to_stdout(Duplicate::All)` also logs everything
+/// to stdout. Duplicated lines use the logger's own format, not the DataDog one
+#[cfg(feature = "tokio-rt")]
+pub async fn init_tokio_logger_with(
+    logger: Logger,
+    datadog_config: DataDogConfig,
+    flush_interval: Option<Duration>,
+) -> Result<(LoggerHandle, JoinHandle<()>), Error> {
+    let (adapter, handle) = spawn_tokio_logger(datadog_config, flush_interval).await?;
+    let logger = logger.log_to_writer(Box::new(adapter)).start()?;
+    Ok((logger, handle))
+}
+
+/// Create and set logger with the writer running on the tokio runtime, returning a
+/// [`DataDogLogger`] that shuts both down in order
+#[cfg(feature = "tokio-rt")]
+pub async fn init_datadog_logger(
+    datadog_config: DataDogConfig,
+    flush_interval: Option<Duration>,
+) -> Result<DataDogLogger, Error> {
+    let (adapter, join) = spawn_tokio_logger(datadog_config, flush_interval).await?;
+    let stats = adapter.stats();
+    let handle = Logger::try_with_env()?
         .log_to_writer(Box::new(adapter))
-        .start()
-        .map(|l| (l, handle))
+        .start()?;
+    Ok(DataDogLogger::from_logger(handle, stats, join))
+}
+
+/// Create and spawn logger on the tokio runtime, returning a [`DataDogLogger`] that shuts both
+/// down in order
+#[cfg(feature = "tokio-rt")]
+pub async fn spawn_datadog_logger(
+    datadog_config: DataDogConfig,
+    flush_interval: Option<Duration>,
+) -> Result<DataDogLogger, Error> {
+    let (adapter, join) = spawn_tokio_logger(datadog_config, flush_interval).await?;
+    Ok(DataDogLogger::from_adapter(adapter, join))
 }
 
 /// Create and spawn logger on the tokio runtime
@@ -34,46 +96,160 @@ pub async fn init_tokio_logger(
 pub async fn spawn_tokio_logger(
     datadog_config: DataDogConfig,
     flush_interval: Option<Duration>,
-) -> (DataDogAdapter, JoinHandle<()>) {
-    let (adapter, mut writer) = new_datadog_http_logger(datadog_config, flush_interval);
+) -> Result<(DataDogAdapter, JoinHandle<()>), Error> {
+    let (adapter, mut writer) = new_datadog_http_logger(datadog_config, flush_interval)?;
     let handle = tokio::spawn(async move { writer.poll().await });
-    (adapter, handle)
+    Ok((adapter, handle))
+}
+
+/// Create and set logger with the writer running on the async-std runtime
+#[cfg(feature = "async-std-rt")]
+pub async fn init_async_std_logger(
+    datadog_config: DataDogConfig,
+    flush_interval: Option<Duration>,
+) -> Result<(LoggerHandle, async_std::task::JoinHandle<()>), Error> {
+    let (adapter, handle) = spawn_async_std_logger(datadog_config, flush_interval).await?;
+    let logger = Logger::try_with_env()?
+        .log_to_writer(Box::new(adapter))
+        .start()?;
+    Ok((logger, handle))
+}
+
+/// Create and spawn logger on the async-std runtime
+#[cfg(feature = "async-std-rt")]
+pub async fn spawn_async_std_logger(
+    datadog_config: DataDogConfig,
+    flush_interval: Option<Duration>,
+) -> Result<(DataDogAdapter, async_std::task::JoinHandle<()>), Error> {
+    let (adapter, mut writer) = new_datadog_http_logger(datadog_config, flush_interval)?;
+    let handle = async_std::task::spawn(async move { writer.poll().await });
+    Ok((adapter, handle))
+}
+
+/// Create logger with the writer polling on a dedicated thread, for applications without an
+/// async runtime. Once the adapter is shut down, joining the returned handle waits for the
+/// writer's final flush
+#[cfg(feature = "thread-rt")]
+pub fn spawn_thread_logger(
+    datadog_config: DataDogConfig,
+    flush_interval: Option<Duration>,
+) -> Result<(DataDogAdapter, thread::JoinHandle<()>), Error> {
+    let (adapter, mut writer) = new_datadog_http_logger(datadog_config, flush_interval)?;
+    // The HTTP client needs a reactor, a single threaded one private to the writer suffices
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let handle = thread::Builder::new()
+        .name("datadog-writer".to_string())
+        .spawn(move || runtime.block_on(writer.poll()))?;
+    Ok((adapter, handle))
 }
 
-/// Create [`DataDogAdapter`] and [`DataDogHttpWriter`].
-/// `writer.poll()` will need to be spawned via a thread or runtime
+/// Create [`DataDogAdapter`] and [`DataDogHttpWriter`], failing if the HTTP client cannot be
+/// built. `writer.poll()` will need to be spawned via a thread or runtime
 pub fn new_datadog_http_logger(
     datadog_config: DataDogConfig,
     flush_interval: Option<Duration>,
-) -> (DataDogAdapter, DataDogHttpWriter) {
-    let (log_sender, log_receiver) = flume::unbounded();
-    let (flush_request_sender, flush_request_receiver) = flume::bounded(0);
-    let (flush_response_sender, flush_response_receiver) = flume::bounded(0);
-    let adapter = DataDogAdapter::new(log_sender, flush_request_sender, flush_response_receiver);
-    let writer = DataDogHttpWriter::new(
+) -> Result<(DataDogAdapter, DataDogHttpWriter), Error> {
+    let (adapter, channels) = new_adapter(&datadog_config);
+    let writer = DataDogHttpWriter::from_channels(datadog_config, flush_interval, channels)?;
+    Ok((adapter, writer))
+}
+
+/// Create [`DataDogAdapter`] with the channels to its writer, returning it with the writer's
+/// ends. The adapter shares the writer's stats and can signal it to flush
+pub(crate) fn new_adapter(datadog_config: &DataDogConfig) -> (DataDogAdapter, WriterChannels) {
+    let (log_sender, logs) = event::channel(datadog_config.channel_capacity);
+    let (flush_request_sender, flush_request) = flume::bounded(0);
+    let (flush_response, flush_response_receiver) = flume::bounded(0);
+    let (flush_signaller, flush_signal) = flume::bounded(1);
+    let stats = Arc::new(WriterStats::default());
+    let adapter = DataDogAdapter::new(
         datadog_config,
-        flush_interval,
+        log_sender,
+        flush_request_sender,
+        flush_response_receiver,
+    )
+    .with_overflow_receiver(logs.clone())
+    .with_stats(stats.clone())
+    .with_flush_signal(flush_signaller);
+    (
+        adapter,
+        WriterChannels {
+            logs,
+            flush_request,
+            flush_response,
+            flush_signal,
+            stats,
+        },
+    )
+}
+
+/// Ship newline delimited lines from the file at `path` to DataDog, e.g. to recover a spool
+/// file after fixing the api key. Returns the number of lines delivered
+pub async fn replay_file(datadog_config: DataDogConfig, path: &Path) -> Result<usize, Error> {
+    let file = BufReader::new(File::open(path)?);
+    let (_, log_receiver) = flume::unbounded();
+    let (_, flush_request_receiver) = flume::bounded(0);
+    let (flush_response_sender, _) = flume::bounded(0);
+    let mut writer = DataDogHttpWriter::new(
+        datadog_config,
+        None,
         log_receiver,
         flush_request_receiver,
         flush_response_sender,
-    );
-    (adapter, writer)
+    )?;
+    writer.replay(file).await
+}
+
+/// Create [`DataDogAdapter`] and [`DataDogTcpWriter`] sending to the TCP intake at `address`,
+/// failing if the writer cannot be built. `writer.poll()` will need to be spawned via a thread
+/// or runtime
+#[cfg(feature = "tcp")]
+pub fn new_datadog_tcp_logger(
+    datadog_config: DataDogConfig,
+    address: String,
+    flush_interval: Option<Duration>,
+) -> Result<(DataDogAdapter, DataDogTcpWriter), Error> {
+    let (adapter, channels) = new_adapter(&datadog_config);
+    let writer =
+        DataDogTcpWriter::from_channels(datadog_config, address, flush_interval, channels)?;
+    Ok((adapter, writer))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::config::{DataDogConfig, DataDogConfigBuilder};
+    use crate::config::{
+        DataDogConfig, DataDogConfigBuilder, DataDogLimits, DataDogSite, FilterResult, GzipMode,
+        LevelRoute, LogFormat, OverflowPolicy, OversizedLinePolicy, PayloadFormat,
+        SendFailurePolicy, TextLayout, TraceIds, MAX_PAYLOAD_BYTES,
+    };
     use crate::error::Error;
-    use crate::{spawn_tokio_logger, DataDogAdapter};
+    use crate::logger::DataDogLogger;
+    #[cfg(feature = "async-std-rt")]
+    use crate::spawn_async_std_logger;
+    #[cfg(feature = "thread-rt")]
+    use crate::spawn_thread_logger;
+    use crate::stats::{BufferDepth, WriterStatsSnapshot};
+    use crate::{
+        new_datadog_http_logger, replay_file, spawn_datadog_logger, spawn_tokio_logger,
+        DataDogAdapter,
+    };
     use anyhow::Result;
+    use arc_swap::ArcSwap;
     use chrono::Duration;
+    use flate2::read::GzDecoder;
     use flexi_logger::writers::LogWriter;
     use flexi_logger::DeferredNow;
+    use httpmock::prelude::HttpMockRequest;
     use httpmock::{Mock, MockServer};
     use itertools::Itertools;
-    use log::{Level, Record};
+    use log::{Level, LevelFilter, Record};
     use std::fmt::Arguments;
     use std::future::Future;
+    use std::io::Read;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
     use std::thread::sleep;
     use std::time;
     use tokio::task::JoinHandle;
@@ -114,7 +290,7 @@ mod tests {
         F: FnOnce(DataDogAdapter) -> Fut,
         Fut: Future<Output = Result<(), Error>>,
     {
-        let (adapter, handle) = spawn_tokio_logger(config, flush_interval).await;
+        let (adapter, handle) = spawn_tokio_logger(config, flush_interval).await?;
         f(adapter).await.map(|_| handle)
     }
 
@@ -164,16 +340,237 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_logger_shutdown() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["DEBUG [] this is a test"]);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_max_buffer_bytes(Some("DEBUG [] this is a test".len()));
+        let logger = spawn_datadog_logger(dd_config.build(), None).await?;
+        let adapter = logger.adapter().expect("adapter is owned by the logger");
+        for _ in 0..2 {
+            adapter.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            )?;
+        }
+        let stats = logger.shutdown().await?;
+        mock.assert();
+        assert_eq!(stats.evicted_lines(), 1);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_logger_shutdown_result() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(
+            &server,
+            vec!["INFO [] first", "INFO [] second", "INFO [] third"],
+        );
+        let logger = spawn_datadog_logger(dd_config(server.base_url()).build(), None).await?;
+        let adapter = logger.adapter().expect("adapter is owned by the logger");
+        for line in ["first", "second", "third"] {
+            adapter.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("{}", line)),
+            )?;
+        }
+        let stats = logger.shutdown().await?;
+        mock.assert();
+        assert_eq!(stats.sent_lines(), 3);
+        assert_eq!(stats.unsent_lines(), 0);
+
+        let failing = MockServer::start();
+        failing.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(500);
+        });
+        let mut dd_config = dd_config(failing.base_url());
+        dd_config.with_shutdown_retries(0);
+        let logger = spawn_datadog_logger(dd_config.build(), None).await?;
+        let stats = logger.stats();
+        logger
+            .adapter()
+            .expect("adapter is owned by the logger")
+            .write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("lost")),
+            )?;
+        assert!(matches!(logger.shutdown().await, Err(Error::FlushError(_))));
+        assert_eq!(stats.unsent_lines(), 1);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_logger_connect() -> Result<()> {
+        let server = MockServer::start();
+        let ping = server.mock(|when, then| {
+            when.method(httpmock::Method::HEAD)
+                .header("DD-API-KEY", "dummy_key");
+            then.status(200);
+        });
+        let mock = mock(&server, vec!["DEBUG [] this is a test"]);
+
+        let logger = DataDogLogger::connect(&dd_config(server.base_url()), None).await?;
+        ping.assert();
+        logger
+            .adapter()
+            .expect("adapter is owned by the logger")
+            .write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            )?;
+        logger.shutdown().await?;
+        mock.assert();
+
+        let forbidden = MockServer::start();
+        forbidden.mock(|when, then| {
+            when.method(httpmock::Method::HEAD);
+            then.status(403);
+        });
+        assert!(matches!(
+            DataDogLogger::connect(&dd_config(forbidden.base_url()), None).await,
+            Err(Error::ApiError { status: 403, .. })
+        ));
+        assert!(matches!(
+            DataDogLogger::connect(dd_config(server.base_url()).with_max_tags(Some(0)), None).await,
+            Err(Error::ConfigError(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_key_provider() -> Result<()> {
+        let server = MockServer::start();
+        let old_key = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .header("DD-API-KEY", "old_key")
+                .body("INFO [] first");
+            then.status(200);
+        });
+        let new_key = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .header("DD-API-KEY", "new_key")
+                .body("INFO [] second");
+            then.status(200);
+        });
+
+        let api_key = Arc::new(ArcSwap::from_pointee("old_key".to_string()));
+        let mut dd_config = dd_config(server.base_url());
+        let provider = api_key.clone();
+        dd_config.with_api_key_provider(move || provider.load().to_string());
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("first")),
+            )?;
+            logger.flush()?;
+            api_key.store(Arc::new("new_key".to_string()));
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("second")),
+            )?;
+            logger.flush()?;
+            old_key.assert();
+            new_key.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_key_header() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .header("Authorization", "Bearer dummy_key")
+                .matches(|req| !has_header(req, "DD-API-KEY"));
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_api_key_header("Authorization")
+            .with_api_key_template("Bearer {api_key}");
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("first")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+
+        dd_config.with_api_key_header("Not a header");
+        assert!(matches!(dd_config.try_build(), Err(Error::ConfigError(_))));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_flush_lines() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["DEBUG [] this is a test"]);
+
+        with_logger(
+            dd_config(server.base_url()).build(),
+            None,
+            |logger| async move {
+                assert_eq!(logger.flush_lines()?, 0);
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("this is a test")),
+                )?;
+                assert_eq!(logger.flush_lines()?, 1);
+                assert_eq!(logger.flush_lines()?, 0);
+                mock.assert();
+                Ok(())
+            },
+        )
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "record")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_recorder() -> Result<()> {
+        use crate::recorder::recorder;
+
+        // No api is listening, the recorder sees lines regardless of delivery
+        let dd_config = dd_config("http://127.0.0.1:1".to_string());
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("recorded message")),
+            )?;
+            let _ = logger.flush();
+            Ok(())
+        })
+        .await?
+        .await?;
+        recorder().assert_contains("INFO [] recorded message");
+        assert!(!recorder().contains("never logged"));
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn test_max_payload() -> Result<()> {
         let server = MockServer::start();
-        let line = "DEBUG [] this is a test";
+        let line = "DEBUG [] this is a test 0";
         let mocks = (0..3)
             .map(|i| mock(&server, vec![&format!("DEBUG [] this is a test {}", i)]))
             .collect_vec();
 
         let mut dd_config = dd_config(server.base_url());
-        dd_config.with_max_payload_size(Some(line.as_bytes().len()));
+        dd_config.with_max_payload_size(Some(line.len()));
 
         with_logger(dd_config.build(), None, |logger| async move {
             for i in 0..3 {
@@ -192,6 +589,41 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_max_payload_compressed() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST).matches(|req| {
+                gunzip(req)
+                    == (0..20)
+                        .map(|_| format!("INFO [] {}", "x".repeat(100)))
+                        .join("\n")
+            });
+            then.status(200);
+        });
+
+        // Compressible lines far beyond the uncompressed limit still fit one gzipped request
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_gzip(GzipMode::Always)
+            .with_max_payload_size(Some(500));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            for _ in 0..20 {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Info, format_args!("{}", "x".repeat(100))),
+                )?;
+            }
+            assert_eq!(logger.flush_lines()?, 20);
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn test_schedule() -> Result<()> {
         let server = MockServer::start();
@@ -214,4 +646,2799 @@ mod tests {
         .await?;
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_schedule_cadence() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["DEBUG [] this is a test"]);
+
+        with_logger(
+            dd_config(server.base_url()).build(),
+            Some(Duration::milliseconds(1000)),
+            |logger| async move {
+                for sent in 1..=2 {
+                    // Let an empty interval elapse, then log half way through the next one
+                    sleep(time::Duration::from_millis(1500));
+                    logger.write(
+                        &mut DeferredNow::new(),
+                        &record(Level::Debug, format_args!("this is a test")),
+                    )?;
+                    sleep(time::Duration::from_millis(250));
+                    mock.assert_hits(sent - 1);
+                    sleep(time::Duration::from_millis(250));
+                }
+                sleep(time::Duration::from_millis(500));
+                mock.assert_hits(2);
+                Ok(())
+            },
+        )
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_metadata() {
+        let config = dd_config("http://localhost".to_string())
+            .with_host_metadata(true)
+            .build();
+        let keys = config.tags.iter().map(|(k, _)| k.as_str()).collect_vec();
+        assert_eq!(keys, vec!["test_key", "os", "arch", "rustc"]);
+        assert!(config
+            .tags
+            .contains(&("os".to_string(), std::env::consts::OS.to_string())));
+        assert!(config
+            .tags
+            .contains(&("arch".to_string(), std::env::consts::ARCH.to_string())));
+    }
+
+    #[test]
+    fn test_env() {
+        let config = dd_config("http://localhost".to_string())
+            .with_tags(vec![("env", "default")])
+            .with_env("staging".to_string())
+            .build();
+        assert_eq!(
+            config.tags,
+            vec![("env".to_string(), "staging".to_string())]
+        );
+
+        std::env::set_var("DD_ENV", "production");
+        let config = dd_config("http://localhost".to_string())
+            .with_env_from_var()
+            .build();
+        assert!(config
+            .tags
+            .contains(&("env".to_string(), "production".to_string())));
+
+        std::env::set_var("DD_ENV", "");
+        let config = dd_config("http://localhost".to_string())
+            .with_env_from_var()
+            .build();
+        assert!(config.tags.iter().all(|(k, _)| k != "env"));
+        std::env::remove_var("DD_ENV");
+    }
+
+    #[test]
+    fn test_site() {
+        let sites = vec![
+            (DataDogSite::US1, "http-intake.logs.datadoghq.com"),
+            (DataDogSite::US3, "http-intake.logs.us3.datadoghq.com"),
+            (DataDogSite::US5, "http-intake.logs.us5.datadoghq.com"),
+            (DataDogSite::EU1, "http-intake.logs.datadoghq.eu"),
+            (DataDogSite::AP1, "http-intake.logs.ap1.datadoghq.com"),
+            (DataDogSite::US1Fed, "http-intake.logs.ddog-gov.com"),
+        ];
+        for (site, host) in sites {
+            let config = DataDogConfigBuilder::new(
+                "host".to_string(),
+                "test".to_string(),
+                "dummy_key".to_string(),
+            )
+            .with_site(site)
+            .build();
+            assert_eq!(config.api_host, format!("https://{}/api/v2/logs", host));
+        }
+
+        // An explicit api host overrides the site
+        let config = dd_config("http://localhost".to_string())
+            .with_site(DataDogSite::EU1)
+            .build();
+        assert_eq!(config.api_host, "http://localhost");
+    }
+
+    #[test]
+    fn test_from_env() -> Result<()> {
+        std::env::set_var("DD_API_KEY", "env_key");
+        std::env::set_var("DD_SITE", "datadoghq.eu");
+        std::env::set_var("DD_SERVICE", "env_service");
+        std::env::set_var("DD_HOSTNAME", "env_host");
+        let config = DataDogConfigBuilder::from_env()?.build();
+        assert_eq!(config.api_key, "env_key");
+        assert_eq!(config.service, "env_service");
+        assert_eq!(config.hostname, "env_host");
+        assert_eq!(
+            config.api_host,
+            "https://http-intake.logs.datadoghq.eu/api/v2/logs"
+        );
+        for var in ["DD_API_KEY", "DD_SITE", "DD_SERVICE", "DD_HOSTNAME"] {
+            std::env::remove_var(var);
+        }
+
+        assert!(matches!(
+            DataDogConfigBuilder::from_env(),
+            Err(Error::MissingEnvVar(var)) if var == "DD_API_KEY"
+        ));
+        assert!(matches!(
+            dd_config("http://localhost".to_string())
+                .api_key_from_env("FLEXI_LOGGER_DATADOG_UNSET_KEY"),
+            Err(Error::MissingEnvVar(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_text_layout() -> Result<()> {
+        let layouts = vec![
+            (TextLayout::Brackets, "DEBUG [my::module] this is a test"),
+            (TextLayout::Colon, "DEBUG my::module: this is a test"),
+            (TextLayout::Pipe, "DEBUG|my::module|this is a test"),
+        ];
+        for (layout, line) in layouts {
+            let server = MockServer::start();
+            let mock = mock(&server, vec![line]);
+
+            let mut dd_config = dd_config(server.base_url());
+            dd_config.with_text_layout(layout);
+
+            with_logger(dd_config.build(), None, |logger| async move {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &Record::builder()
+                        .level(Level::Debug)
+                        .module_path(Some("my::module"))
+                        .args(format_args!("this is a test"))
+                        .build(),
+                )?;
+                logger.flush()?;
+                mock.assert();
+                Ok(())
+            })
+            .await?
+            .await?;
+        }
+        Ok(())
+    }
+
+    fn has_header(req: &HttpMockRequest, name: &str) -> bool {
+        req.headers
+            .iter()
+            .flatten()
+            .any(|(k, _)| k.eq_ignore_ascii_case(name))
+    }
+
+    fn gunzip(req: &HttpMockRequest) -> String {
+        let mut body = String::new();
+        GzDecoder::new(req.body.as_deref().unwrap_or_default())
+            .read_to_string(&mut body)
+            .map(|_| body)
+            .unwrap_or_default()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_module_path() -> Result<()> {
+        let cases = vec![
+            (None, Some(2), "DEBUG [db::pool] test"),
+            (None, Some(10), "DEBUG [my_service::infra::db::pool] test"),
+            (Some("my_service"), None, "DEBUG [infra::db::pool] test"),
+            (Some("my_service::"), Some(1), "DEBUG [pool] test"),
+            (
+                Some("my_serv"),
+                None,
+                "DEBUG [my_service::infra::db::pool] test",
+            ),
+        ];
+        for (prefix, depth, line) in cases {
+            let server = MockServer::start();
+            let mock = mock(&server, vec![line]);
+
+            let mut dd_config = dd_config(server.base_url());
+            dd_config
+                .with_module_path_strip_prefix(prefix.map(|p| p.to_string()))
+                .with_module_path_depth(depth);
+
+            with_logger(dd_config.build(), None, |logger| async move {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &Record::builder()
+                        .level(Level::Debug)
+                        .module_path(Some("my_service::infra::db::pool"))
+                        .args(format_args!("test"))
+                        .build(),
+                )?;
+                logger.flush()?;
+                mock.assert();
+                Ok(())
+            })
+            .await?
+            .await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_connection_warmup() -> Result<()> {
+        let server = MockServer::start();
+        let warmup = server.mock(|when, then| {
+            when.method(httpmock::Method::HEAD);
+            then.status(405);
+        });
+        let mock = mock(&server, vec!["DEBUG [] this is a test"]);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_connection_warmup(true);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            sleep(time::Duration::from_millis(200));
+            warmup.assert();
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_trim_trailing_newline() -> Result<()> {
+        for (trim, line) in [(true, "DEBUG [] bridged"), (false, "DEBUG [] bridged\n")] {
+            let server = MockServer::start();
+            let mock = mock(&server, vec![line]);
+
+            let mut dd_config = dd_config(server.base_url());
+            dd_config.with_trim_trailing_newline(trim);
+
+            with_logger(dd_config.build(), None, |logger| async move {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("bridged\n")),
+                )?;
+                logger.flush()?;
+                mock.assert();
+                Ok(())
+            })
+            .await?
+            .await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_max_channel_dwell() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["DEBUG [] fresh"]);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_max_channel_dwell(Some(Duration::milliseconds(200)));
+        let (adapter, mut writer) = new_datadog_http_logger(dd_config.build(), None)?;
+
+        // Stall the writer so the first line waits in the channel
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Debug, format_args!("stale")),
+        )?;
+        sleep(time::Duration::from_millis(300));
+        let handle = tokio::spawn(async move { writer.poll().await });
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Debug, format_args!("fresh")),
+        )?;
+        adapter.flush()?;
+        mock.assert();
+
+        let stats = adapter.stats();
+        assert_eq!(stats.received_lines(), 2);
+        assert_eq!(stats.stale_lines(), 1);
+        assert!(stats.max_channel_dwell() >= time::Duration::from_millis(300));
+        std::mem::drop(adapter);
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_channel_overflow() -> Result<()> {
+        for (policy, lines) in [
+            (
+                OverflowPolicy::DropNewest,
+                vec!["INFO [] first", "INFO [] second"],
+            ),
+            (
+                OverflowPolicy::DropOldest,
+                vec!["INFO [] second", "INFO [] third"],
+            ),
+        ] {
+            let server = MockServer::start();
+            let mock = mock(&server, lines);
+
+            let mut dd_config = dd_config(server.base_url());
+            dd_config
+                .with_channel_capacity(Some(2))
+                .with_overflow_policy(policy);
+            let (adapter, mut writer) = new_datadog_http_logger(dd_config.build(), None)?;
+
+            // Fill the channel before the writer polls, the third line must not block
+            for message in ["first", "second", "third"] {
+                adapter.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Info, format_args!("{}", message)),
+                )?;
+            }
+            let handle = tokio::spawn(async move { writer.poll().await });
+            adapter.flush()?;
+            mock.assert();
+            assert_eq!(adapter.stats().overflow_lines(), 1);
+            std::mem::drop(adapter);
+            handle.await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_high_water_mark() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(
+            &server,
+            vec!["INFO [] 0", "INFO [] 1", "INFO [] 2", "INFO [] 3"],
+        );
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_channel_capacity(Some(4))
+            .with_high_water_mark(Some(0.5));
+        let (adapter, mut writer) = new_datadog_http_logger(dd_config.build(), None)?;
+
+        // Fill the channel before the writer polls, crossing the mark is reported once
+        for i in 0..4 {
+            adapter.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("{}", i)),
+            )?;
+            assert_eq!(adapter.pressure(), (i + 1) as f64 / 4.0);
+            assert_eq!(adapter.stats().high_water_crossings(), (i >= 1) as usize);
+        }
+        let handle = tokio::spawn(async move { writer.poll().await });
+        adapter.flush()?;
+        mock.assert();
+        assert_eq!(adapter.pressure(), 0.0);
+        assert_eq!(adapter.stats().high_water_crossings(), 1);
+        std::mem::drop(adapter);
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_flush_and_shutdown_timeout() -> Result<()> {
+        let server = MockServer::start();
+        let stalled = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200).delay(time::Duration::from_secs(2));
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_flush_timeout(Some(Duration::milliseconds(200)))
+            .with_shutdown_timeout(Some(Duration::milliseconds(300)));
+        let (adapter, mut writer) = new_datadog_http_logger(dd_config.build(), None)?;
+        let handle = tokio::spawn(async move { writer.poll().await });
+
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Debug, format_args!("first")),
+        )?;
+        let started = time::Instant::now();
+        let error = adapter.flush().expect_err("flush should time out");
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+
+        // The writer is still stuck on the first request, shutdown must not wait for it
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Debug, format_args!("second")),
+        )?;
+        let started = time::Instant::now();
+        std::mem::drop(adapter);
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+
+        handle.await?;
+        stalled.assert_hits(2);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_flush_blocking() -> Result<()> {
+        let server = MockServer::start();
+        let sent = mock(&server, vec!["DEBUG [] first"]);
+        let stalled = server.mock(|when, then| {
+            when.method(httpmock::Method::POST).body("DEBUG [] second");
+            then.status(200).delay(time::Duration::from_secs(2));
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_shutdown_timeout(Some(Duration::milliseconds(300)));
+        let (adapter, mut writer) = new_datadog_http_logger(dd_config.build(), None)?;
+        let handle = tokio::spawn(async move { writer.poll().await });
+
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Debug, format_args!("first")),
+        )?;
+        assert_eq!(
+            adapter.flush_blocking(Some(Duration::seconds(1))).ok(),
+            Some(1)
+        );
+        sent.assert();
+
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Debug, format_args!("second")),
+        )?;
+        let result = adapter.flush_blocking(Some(Duration::milliseconds(200)));
+        assert!(matches!(result, Err(Error::FlushTimeout)));
+        stalled.assert();
+        std::mem::drop(adapter);
+        handle.await?;
+
+        let (adapter, writer) = new_datadog_http_logger(dd_config.build(), None)?;
+        std::mem::drop(writer);
+        let result = adapter.flush_blocking(Some(Duration::seconds(1)));
+        assert!(matches!(result, Err(Error::WriterGone)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_after_runtime_shutdown() -> Result<()> {
+        let (adapter, mut writer) =
+            new_datadog_http_logger(dd_config("http://localhost".to_string()).build(), None)?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        // Abort the writer before it runs, then end its runtime, dropping the writer
+        let task = runtime.spawn(async move { writer.poll().await });
+        task.abort();
+        std::mem::drop(runtime);
+
+        assert!(adapter
+            .write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("stranded")),
+            )
+            .is_err());
+        assert!(matches!(
+            adapter.flush_blocking(None),
+            Err(Error::WriterGone)
+        ));
+        let (dropped, done) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            std::mem::drop(adapter);
+            dropped.send(()).ok();
+        });
+        done.recv_timeout(time::Duration::from_secs(1))?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_request_flush() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST).body("DEBUG [] nudged");
+            then.status(200).delay(time::Duration::from_millis(500));
+        });
+
+        with_logger(
+            dd_config(server.base_url()).build(),
+            None,
+            |logger| async move {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("nudged")),
+                )?;
+                let started = time::Instant::now();
+                logger.request_flush()?;
+                assert!(started.elapsed() < time::Duration::from_millis(100));
+                while mock.hits() == 0 && started.elapsed() < time::Duration::from_secs(2) {
+                    sleep(time::Duration::from_millis(10));
+                }
+                mock.assert();
+                Ok(())
+            },
+        )
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_wake_on_activity() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["DEBUG [] this is a test"]);
+        let mut builder = dd_config(server.base_url());
+        builder.with_max_log_lines(Some(1));
+
+        with_logger(builder.build(), None, |logger| async move {
+            // Each line fills the buffer and should be sent without waiting out a poll timeout
+            let started = time::Instant::now();
+            for sent in 1..=5 {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("this is a test")),
+                )?;
+                while mock.hits() < sent && started.elapsed() < time::Duration::from_secs(2) {
+                    sleep(time::Duration::from_millis(1));
+                }
+            }
+            mock.assert_hits(5);
+            assert!(started.elapsed() < time::Duration::from_millis(150));
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_json_lines() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .header("content-type", "text/plain")
+                .matches(|req| {
+                    let body = String::from_utf8_lossy(req.body.as_deref().unwrap_or_default());
+                    let lines = body
+                        .lines()
+                        .map(serde_json::from_str::<serde_json::Value>)
+                        .collect::<Result<Vec<_>, _>>();
+                    matches!(lines, Ok(lines) if lines.len() == 2
+                        && lines[0]["message"] == "first"
+                        && lines[1]["status"] == "warn"
+                        && lines[1]["logger"]["name"] == "my::module")
+                });
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_log_format(LogFormat::JsonLines);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("first")),
+            )?;
+            logger.write(
+                &mut DeferredNow::new(),
+                &Record::builder()
+                    .level(Level::Warn)
+                    .module_path(Some("my::module"))
+                    .args(format_args!("second \"quoted\""))
+                    .build(),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_json_payload() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .header("content-type", "application/json")
+                .matches(|req| {
+                    let body = req.body.as_deref().unwrap_or_default();
+                    let logs = serde_json::from_slice::<serde_json::Value>(body);
+                    matches!(logs, Ok(serde_json::Value::Array(logs)) if logs.len() == 2
+                        && logs[0]["message"].as_str().is_some_and(|m| m.ends_with("first"))
+                        && logs[1]["message"].as_str().is_some_and(|m| m.ends_with("second"))
+                        && logs.iter().all(|log| log["hostname"] == "host"
+                            && log["service"] == "test"
+                            && log["ddsource"] == "rust"
+                            && log["ddtags"] == "test_key:test_value"
+                            && log["date"].is_i64()
+                            && log.get("buffered_ms").is_none()))
+                });
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_payload_format(PayloadFormat::Json);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("first")),
+            )?;
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("second")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_global_attributes() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST).matches(|req| {
+                let body = req.body.as_deref().unwrap_or_default();
+                let logs = serde_json::from_slice::<serde_json::Value>(body);
+                matches!(logs, Ok(serde_json::Value::Array(logs)) if logs.len() == 2
+                    && logs.iter().all(|log| log["build"]["version"] == "1.2.3"
+                        && log["service"] == "test")
+                    && logs[0]["region"] == "us-east-1"
+                    && logs[1]["region"] == "eu-west-1")
+            });
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_payload_format(PayloadFormat::Json)
+            .with_attributes(serde_json::json!({
+                "region": "us-east-1",
+                "service": "overridden",
+                "build": {"version": "1.2.3"},
+            }));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("first")),
+            )?;
+            logger.write(
+                &mut DeferredNow::new(),
+                &Record::builder()
+                    .level(Level::Info)
+                    .args(format_args!("second"))
+                    .key_values(&[("region", log::kv::Value::from("eu-west-1"))])
+                    .build(),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+
+        assert!(dd_config
+            .with_attributes(serde_json::json!(["not", "an", "object"]))
+            .try_build()
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_json_payload_status() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST).matches(|req| {
+                let body = req.body.as_deref().unwrap_or_default();
+                let logs = serde_json::from_slice::<serde_json::Value>(body);
+                matches!(logs, Ok(serde_json::Value::Array(logs)) if logs.len() == 3
+                    && logs[0]["status"] == "error"
+                    && logs[1]["status"] == "warn"
+                    && logs[2]["status"] == "debug")
+            });
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_payload_format(PayloadFormat::Json)
+            .with_status_mapping(Level::Trace, "debug");
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            for level in [Level::Error, Level::Warn, Level::Trace] {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(level, format_args!("status")),
+                )?;
+            }
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_status_from_kv() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST).matches(|req| {
+                let expected = [
+                    "emerg", "alert", "critical", "error", "warn", "notice", "info", "debug",
+                    // Out of range and non-numeric severities keep the level's status
+                    "info", "info",
+                ];
+                let body = req.body.as_deref().unwrap_or_default();
+                let logs = serde_json::from_slice::<serde_json::Value>(body);
+                matches!(logs, Ok(serde_json::Value::Array(logs)) if logs.len() == expected.len()
+                    && logs.iter().zip(expected).all(|(log, status)| log["status"] == status))
+            });
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_payload_format(PayloadFormat::Json)
+            .with_status_from_kv("syslog_severity");
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            let severities = (0..=8)
+                .map(log::kv::Value::from)
+                .chain([log::kv::Value::from("loud")]);
+            for severity in severities {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &Record::builder()
+                        .level(Level::Info)
+                        .args(format_args!("syslog"))
+                        .key_values(&[("syslog_severity", severity)])
+                        .build(),
+                )?;
+            }
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_buffer_latency_attr() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST).matches(|req| {
+                let body = req.body.as_deref().unwrap_or_default();
+                let logs = serde_json::from_slice::<serde_json::Value>(body);
+                // The first line waited in the buffer while the second was logged
+                matches!(logs, Ok(serde_json::Value::Array(logs)) if logs.len() == 2
+                    && logs[0]["buffered_ms"].as_i64().is_some_and(|ms| ms >= 50)
+                    && logs[1]["buffered_ms"].as_i64().is_some_and(|ms| ms >= 0))
+            });
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_payload_format(PayloadFormat::Json)
+            .with_buffer_latency_attr(true);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("first")),
+            )?;
+            sleep(time::Duration::from_millis(50));
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("second")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_otlp_json_payload() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .header("content-type", "application/json")
+                .matches(|req| {
+                    let body = req.body.as_deref().unwrap_or_default();
+                    let Ok(request) = serde_json::from_slice::<serde_json::Value>(body) else {
+                        return false;
+                    };
+                    let resource_logs = &request["resourceLogs"][0];
+                    let records = &resource_logs["scopeLogs"][0]["logRecords"];
+                    resource_logs["resource"]["attributes"]
+                        .as_array()
+                        .is_some_and(|attributes| {
+                            attributes.contains(&serde_json::json!({
+                                "key": "service.name",
+                                "value": { "stringValue": "test" },
+                            }))
+                        })
+                        && records[0]["severityNumber"] == 17
+                        && records[0]["severityText"] == "ERROR"
+                        && records[0]["body"]["stringValue"] == "ERROR [] failed"
+                        && records[0]["timeUnixNano"]
+                            .as_str()
+                            .and_then(|nanos| nanos.parse::<u64>().ok())
+                            .is_some_and(|nanos| nanos > 1_000_000_000_000_000_000)
+                        && records[0]["attributes"][0]
+                            == serde_json::json!({
+                                "key": "attempt",
+                                "value": { "intValue": "3" },
+                            })
+                });
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_payload_format(PayloadFormat::OtlpJson);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &Record::builder()
+                    .level(Level::Error)
+                    .args(format_args!("failed"))
+                    .key_values(&[("attempt", 3)])
+                    .build(),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_trace_context() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST).matches(|req| {
+                let body = req.body.as_deref().unwrap_or_default();
+                let logs = serde_json::from_slice::<serde_json::Value>(body);
+                matches!(logs, Ok(serde_json::Value::Array(logs)) if logs.len() == 2
+                    && logs[0]["dd.trace_id"] == "1234"
+                    && logs[0]["dd.span_id"] == "5678"
+                    && logs[1].get("dd.trace_id").is_none())
+            });
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_payload_format(PayloadFormat::Json)
+            .with_trace_context(|record| {
+                let key_values = record.key_values();
+                Some(TraceIds {
+                    trace_id: key_values.get("trace_id".into())?.to_string(),
+                    span_id: key_values.get("span_id".into())?.to_string(),
+                })
+            });
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &Record::builder()
+                    .level(Level::Info)
+                    .args(format_args!("traced"))
+                    .key_values(&[("trace_id", "1234"), ("span_id", "5678")])
+                    .build(),
+            )?;
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("untraced")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_auto_hostname() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST).matches(|req| {
+                req.query_params
+                    .iter()
+                    .flatten()
+                    .any(|(k, v)| k == "host" && !v.is_empty())
+            });
+            then.status(200);
+        });
+
+        let mut dd_config =
+            DataDogConfigBuilder::new(String::new(), "test".to_string(), "dummy_key".to_string());
+        dd_config
+            .with_auto_hostname()
+            .with_api_host(Some(server.base_url()));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("first")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_add_tag() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .query_param("ddtags", "test_key:test_value,env:prod,env:canary,beta");
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .add_tag("env", "prod")
+            .add_tag("env", "canary")
+            .add_bare_tag("beta");
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("first")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_custom_source() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .query_param("ddsource", "python");
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_source("python");
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("first")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_level_routing() -> Result<()> {
+        let server = MockServer::start();
+        let alerts = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .query_param("service", "alerts")
+                .query_param("ddtags", "test_key:test_value,severity:high")
+                .body("ERROR [] failed");
+            then.status(200);
+        });
+        let test = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .query_param("service", "test")
+                .query_param("ddtags", "test_key:test_value")
+                .body("INFO [] started");
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_level_routing(|level| {
+            (level == Level::Error).then(|| LevelRoute {
+                service: Some("alerts".to_string()),
+                tags: vec![("severity".to_string(), "high".to_string())],
+            })
+        });
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Error, format_args!("failed")),
+            )?;
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("started")),
+            )?;
+            logger.flush()?;
+            alerts.assert();
+            test.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_source_mapping() -> Result<()> {
+        let server = MockServer::start();
+        let postgres = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .query_param("ddsource", "postgresql")
+                .body("DEBUG [app::db] query\nDEBUG [app::db] commit");
+            then.status(200);
+        });
+        let rust = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .query_param("ddsource", "rust")
+                .body("DEBUG [app::web] request");
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_source_mapping(|record| {
+            record
+                .module_path()
+                .filter(|m| m.ends_with("::db"))
+                .map(|_| "postgresql".to_string())
+        });
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            for (module, message) in [
+                ("app::db", "query"),
+                ("app::web", "request"),
+                ("app::db", "commit"),
+            ] {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &Record::builder()
+                        .level(Level::Debug)
+                        .module_path(Some(module))
+                        .args(format_args!("{}", message))
+                        .build(),
+                )?;
+            }
+            logger.flush()?;
+            postgres.assert();
+            rust.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_key_values() -> Result<()> {
+        let server = MockServer::start();
+        let text = mock(&server, vec!["INFO [] signed in attempt=3 user=alice"]);
+        with_logger(
+            dd_config(server.base_url()).build(),
+            None,
+            |logger| async move {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &Record::builder()
+                        .level(Level::Info)
+                        .args(format_args!("signed in"))
+                        .key_values(&[
+                            ("user", log::kv::Value::from("alice")),
+                            ("attempt", log::kv::Value::from(3)),
+                        ])
+                        .build(),
+                )?;
+                logger.flush()?;
+                text.assert();
+                Ok(())
+            },
+        )
+        .await?
+        .await?;
+
+        let server = MockServer::start();
+        let json = server.mock(|when, then| {
+            when.method(httpmock::Method::POST).matches(|req| {
+                let body = req.body.as_deref().unwrap_or_default();
+                let logs = serde_json::from_slice::<serde_json::Value>(body);
+                matches!(logs, Ok(serde_json::Value::Array(logs)) if logs.len() == 1
+                    && logs[0]["message"] == "INFO [] signed in"
+                    && logs[0]["user"] == "alice"
+                    && logs[0]["attempt"] == 3)
+            });
+            then.status(200);
+        });
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_payload_format(PayloadFormat::Json);
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &Record::builder()
+                    .level(Level::Info)
+                    .args(format_args!("signed in"))
+                    .key_values(&[
+                        ("user", log::kv::Value::from("alice")),
+                        ("attempt", log::kv::Value::from(3)),
+                    ])
+                    .build(),
+            )?;
+            logger.flush()?;
+            json.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_custom_format() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["custom WARN formatted"]);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_format(|_now, record| format!("custom {} {}", record.level(), record.args()));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Warn, format_args!("formatted")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_line_timestamp() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST).matches(|req| {
+                let body = String::from_utf8_lossy(req.body.as_deref().unwrap_or_default());
+                matches!(body.split_once(' '), Some((timestamp, line))
+                    if chrono::DateTime::parse_from_rfc3339(timestamp).is_ok()
+                        && line == "INFO [] stamped")
+            });
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_line_timestamp(true);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("stamped")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "async-std-rt")]
+    #[test]
+    fn test_async_std_logger() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["INFO [] async-std"]);
+
+        async_std::task::block_on(async {
+            let (adapter, handle) =
+                spawn_async_std_logger(dd_config(server.base_url()).build(), None).await?;
+            adapter.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("async-std")),
+            )?;
+            adapter.shutdown();
+            handle.await;
+            Ok::<_, Error>(())
+        })?;
+        mock.assert();
+        Ok(())
+    }
+
+    #[cfg(feature = "thread-rt")]
+    #[test]
+    fn test_thread_logger() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["INFO [] threaded"]);
+
+        let (adapter, handle) = spawn_thread_logger(dd_config(server.base_url()).build(), None)?;
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Info, format_args!("threaded")),
+        )?;
+        adapter.shutdown();
+        handle.join().expect("writer thread panicked");
+        mock.assert();
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_gzip_auto() -> Result<()> {
+        let server = MockServer::start();
+        let small = server.mock(|when, then| {
+            when.matches(|req| !has_header(req, "content-encoding"))
+                .body("DEBUG [] small");
+            then.status(200);
+        });
+        let large = server.mock(|when, then| {
+            when.header("content-encoding", "gzip").matches(|req| {
+                gunzip(req) == "DEBUG [] this is a larger test\nDEBUG [] this is a larger test"
+            });
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_gzip(GzipMode::Auto(32));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("small")),
+            )?;
+            logger.flush()?;
+            small.assert();
+            for _ in 0..2 {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("this is a larger test")),
+                )?;
+            }
+            logger.flush()?;
+            large.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "stream")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_streaming_upload() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.header("content-encoding", "gzip").matches(|req| {
+                gunzip(req) == (0..100).map(|i| format!("DEBUG [] line {}", i)).join("\n")
+            });
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_gzip(GzipMode::Always)
+            .with_streaming_upload(true);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            for i in 0..100 {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("line {}", i)),
+                )?;
+            }
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_filter() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["DEBUG [] keep me", "rewritten"]);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_filter(|record| match record.args().to_string().as_str() {
+            "drop me" => FilterResult::Drop,
+            "rewrite me" => FilterResult::Rewrite("rewritten".to_string()),
+            _ => FilterResult::Keep,
+        });
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            for message in ["keep me", "drop me", "rewrite me"] {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("{}", message)),
+                )?;
+            }
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_failure_policy() {
+        let policies = vec![
+            (SendFailurePolicy::ReturnErr, true),
+            (SendFailurePolicy::SilentDrop, false),
+            (SendFailurePolicy::Stderr, false),
+        ];
+        for (policy, is_err) in policies {
+            let mut dd_config = dd_config("http://localhost".to_string());
+            dd_config.with_send_failure_policy(policy);
+            let (log_sender, _) = flume::unbounded();
+            let (flush_request_sender, _) = flume::bounded(0);
+            let (_, flush_response_receiver) = flume::bounded(0);
+            let adapter = DataDogAdapter::new(
+                &dd_config.build(),
+                log_sender,
+                flush_request_sender,
+                flush_response_receiver,
+            );
+            let result = adapter.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            );
+            assert_eq!(result.is_err(), is_err, "{:?}", policy);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_max_buffer_bytes() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["DEBUG [] line 2", "DEBUG [] line 3"]);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_max_buffer_bytes(Some(40));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            for i in 1..=3 {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("line {}", i)),
+                )?;
+            }
+            logger.flush()?;
+            mock.assert();
+            assert_eq!(logger.stats().evicted_lines(), 1);
+            assert_eq!(logger.stats().evicted_bytes(), 15);
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_max_buffer_bytes_outage() -> Result<()> {
+        let server = MockServer::start();
+        let outage = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(503);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_max_buffer_bytes(Some(40));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            // Failed sends keep the lines buffered, the cap must still hold
+            for i in 1..=5 {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("line {}", i)),
+                )?;
+                assert!(logger.flush().is_err());
+            }
+            outage.assert_hits(5);
+            assert_eq!(logger.stats().evicted_lines(), 3);
+            assert_eq!(logger.stats().evicted_bytes(), 45);
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_max_log_lines_after_failed_flush() -> Result<()> {
+        static FAILING: AtomicBool = AtomicBool::new(true);
+        let server = MockServer::start();
+        let failure = server.mock(|when, then| {
+            when.matches(|_| FAILING.load(Ordering::SeqCst));
+            then.status(503);
+        });
+        let oversized = server.mock(|when, then| {
+            when.matches(|req| {
+                let body = req.body.as_deref().unwrap_or_default();
+                body.split(|b| *b == b'\n').count() > 2
+            });
+            then.status(200);
+        });
+        let success = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_max_log_lines(Some(2));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            // Failed flushes keep their lines, so the buffer grows past the per request limit
+            for i in 1..=5 {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("line {}", i)),
+                )?;
+                assert!(logger.flush().is_err());
+            }
+            assert_eq!(logger.stats().buffered_lines(), 5);
+            FAILING.store(false, Ordering::SeqCst);
+
+            logger.flush()?;
+            assert!(failure.hits() >= 5);
+            oversized.assert_hits(0);
+            success.assert_hits(3);
+            assert_eq!(logger.stats().sent_lines(), 5);
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_max_retries() -> Result<()> {
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+        let server = MockServer::start();
+        let failure = server.mock(|when, then| {
+            when.matches(|_| ATTEMPTS.fetch_add(1, Ordering::SeqCst) < 2);
+            then.status(503);
+        });
+        let success = mock(&server, vec!["DEBUG [] this is a test"]);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_max_retries(2)
+            .with_retry_base_delay(Duration::milliseconds(10));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            )?;
+            logger.flush()?;
+            failure.assert_hits(2);
+            success.assert();
+            assert_eq!(logger.stats().retries(), 2);
+            assert_eq!(logger.stats().sent_lines(), 1);
+            Ok(())
+        })
+        .await?
+        .await?;
+
+        // Client errors other than 429 are not retried, and the lines are kept for later
+        let server = MockServer::start();
+        let bad_request = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(400);
+        });
+        dd_config.with_api_host(Some(server.base_url()));
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            )?;
+            assert!(logger.flush().is_err());
+            bad_request.assert_hits(1);
+            assert!(logger.flush().is_err());
+            bad_request.assert_hits(2);
+            assert_eq!(logger.stats().failed_batches(), 2);
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_retry_after() -> Result<()> {
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+        let server = MockServer::start();
+        let rate_limited = server.mock(|when, then| {
+            when.matches(|_| ATTEMPTS.fetch_add(1, Ordering::SeqCst) < 1);
+            then.status(429).header("Retry-After", "1");
+        });
+        let success = mock(&server, vec!["DEBUG [] this is a test"]);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_max_retries(1);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            )?;
+            let started = time::Instant::now();
+            logger.flush()?;
+            assert!(started.elapsed() >= time::Duration::from_secs(1));
+            rate_limited.assert();
+            success.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_retry_after_yields() -> Result<()> {
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+        let server = MockServer::start();
+        let rate_limited = server.mock(|when, then| {
+            when.matches(|_| ATTEMPTS.fetch_add(1, Ordering::SeqCst) < 1);
+            then.status(429).header("Retry-After", "1");
+        });
+        let success = mock(&server, vec!["DEBUG [] this is a test"]);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_max_retries(1).with_max_log_lines(Some(1));
+        let (adapter, handle) = spawn_tokio_logger(dd_config.build(), None).await?;
+
+        // Shares the single worker thread with the writer, so it only ticks while the writer
+        // waits without blocking the thread
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(time::Duration::from_millis(10)).await;
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Debug, format_args!("this is a test")),
+        )?;
+        let started = time::Instant::now();
+        while rate_limited.hits() == 0 && started.elapsed() < time::Duration::from_secs(2) {
+            tokio::time::sleep(time::Duration::from_millis(5)).await;
+        }
+        let waiting = ticks.load(Ordering::SeqCst);
+        while success.hits() == 0 && started.elapsed() < time::Duration::from_secs(3) {
+            tokio::time::sleep(time::Duration::from_millis(5)).await;
+        }
+        rate_limited.assert();
+        success.assert();
+        assert!(ticks.load(Ordering::SeqCst) - waiting >= 20);
+
+        ticker.abort();
+        std::mem::drop(adapter);
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_shutdown_timeout_retry_after() -> Result<()> {
+        let server = MockServer::start();
+        let rate_limited = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(429).header("Retry-After", "10");
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_max_retries(3)
+            .with_shutdown_timeout(Some(Duration::milliseconds(300)));
+        let (adapter, mut writer) = new_datadog_http_logger(dd_config.build(), None)?;
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Debug, format_args!("this is a test")),
+        )?;
+        // Closes the channels before the writer runs, so its final flush is the one retrying
+        std::mem::drop(adapter);
+
+        let stats = writer.stats();
+        let started = time::Instant::now();
+        writer.poll().await;
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+        rate_limited.assert();
+        assert_eq!(stats.unsent_lines(), 1);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_request_timeout() -> Result<()> {
+        let server = MockServer::start();
+        let slow = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200).delay(time::Duration::from_secs(2));
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_request_timeout(Duration::milliseconds(200));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            )?;
+            let started = time::Instant::now();
+            let error = logger.flush().expect_err("flush should time out");
+            assert!(started.elapsed() < time::Duration::from_secs(2));
+            assert!(matches!(
+                error.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+                Some(Error::HttpError(e)) if e.is_timeout()
+            ));
+            slow.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_injected_client() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .header("x-injected", "yes")
+                .body("DEBUG [] this is a test");
+            then.status(200);
+        });
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("x-injected", "yes".parse()?);
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()?;
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_client(client);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_writer_stats() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["INFO [] counted"]);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_gzip(GzipMode::Never)
+            .with_oversized_line_policy(OversizedLinePolicy::Drop);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("counted")),
+            )?;
+            let oversized = "x".repeat(MAX_PAYLOAD_BYTES);
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("{}", oversized)),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            assert_eq!(
+                logger.stats().snapshot(),
+                WriterStatsSnapshot {
+                    received_lines: 2,
+                    sent_lines: 1,
+                    sent_bytes: "INFO [] counted".len(),
+                    oversized_lines: 1,
+                    ..Default::default()
+                }
+            );
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_buffer_depth() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["INFO [] first", "INFO [] second"]);
+
+        with_logger(
+            dd_config(server.base_url()).build(),
+            None,
+            |logger| async move {
+                assert_eq!(logger.buffer_depth(), BufferDepth::default());
+                for line in ["first", "second"] {
+                    logger.write(
+                        &mut DeferredNow::new(),
+                        &record(Level::Info, format_args!("{}", line)),
+                    )?;
+                }
+                // Lines move from the channel to the buffer as the writer receives them
+                let mut depth = logger.buffer_depth();
+                for _ in 0..100 {
+                    if depth.buffered_lines == 2 {
+                        break;
+                    }
+                    assert_eq!(depth.queued_lines + depth.buffered_lines, 2);
+                    sleep(time::Duration::from_millis(10));
+                    depth = logger.buffer_depth();
+                }
+                assert_eq!(
+                    depth,
+                    BufferDepth {
+                        queued_lines: 0,
+                        buffered_lines: 2,
+                        buffered_bytes: "INFO [] first".len() + "INFO [] second".len(),
+                    }
+                );
+                logger.flush()?;
+                mock.assert();
+                assert_eq!(logger.buffer_depth(), BufferDepth::default());
+                Ok(())
+            },
+        )
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_oversized_line_truncate() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(
+            &server,
+            vec!["INFO [] xxxxxxxx...[truncated]", "INFO [] short"],
+        );
+
+        // Truncating is the default policy
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_max_line_size(Some(30));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            for line in ["x".repeat(30), "short".to_string()] {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Info, format_args!("{}", line)),
+                )?;
+            }
+            logger.flush()?;
+            mock.assert();
+            assert_eq!(logger.stats().oversized_lines(), 0);
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_oversized_line_truncate_default() -> Result<()> {
+        let server = MockServer::start();
+        // Without a configured max line size lines are truncated to DataDog's 1MB line limit
+        let truncated = format!(
+            "INFO [] {}...[truncated]",
+            "x".repeat(1000000 - "INFO [] ...[truncated]".len())
+        );
+        let mock = mock(&server, vec![&truncated]);
+
+        with_logger(
+            dd_config(server.base_url()).build(),
+            None,
+            |logger| async move {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Info, format_args!("{}", "x".repeat(1000000))),
+                )?;
+                logger.flush()?;
+                mock.assert();
+                assert_eq!(logger.stats().oversized_lines(), 0);
+                Ok(())
+            },
+        )
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_oversized_line_drop() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["INFO [] short"]);
+        let dropped = Arc::new(Mutex::new(vec![]));
+
+        let mut dd_config = dd_config(server.base_url());
+        let on_dropped = dropped.clone();
+        dd_config
+            .with_max_line_size(Some(20))
+            .with_oversized_line_policy(OversizedLinePolicy::Drop)
+            .with_on_dropped(move |line| on_dropped.lock().unwrap().push(line.to_string()));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            for line in ["x".repeat(30), "short".to_string()] {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Info, format_args!("{}", line)),
+                )?;
+            }
+            logger.flush()?;
+            mock.assert();
+            assert_eq!(logger.stats().oversized_lines(), 1);
+            Ok(())
+        })
+        .await?
+        .await?;
+        assert_eq!(
+            *dropped.lock().unwrap(),
+            vec![format!("INFO [] {}", "x".repeat(30))]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_shutdown_retries() -> Result<()> {
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+        let server = MockServer::start();
+        // Fail the flush requested by the adapter on shutdown and the writer's final flush
+        let failure = server.mock(|when, then| {
+            when.matches(|_| ATTEMPTS.fetch_add(1, Ordering::SeqCst) < 2);
+            then.status(500);
+        });
+        let success = mock(&server, vec!["DEBUG [] this is a test"]);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_shutdown_retries(1);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            )?;
+            Ok(())
+        })
+        .await?
+        .await?;
+        failure.assert_hits(2);
+        success.assert();
+        Ok(())
+    }
+
+    /// Installs a shared adapter as the global logger's writer, so it can still be changed
+    struct SharedAdapter(Arc<DataDogAdapter>);
+
+    impl LogWriter for SharedAdapter {
+        fn write(&self, now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+            self.0.write(now, record)
+        }
+
+        fn flush(&self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+
+        fn max_log_level(&self) -> LevelFilter {
+            self.0.max_log_level()
+        }
+
+        fn shutdown(&self) {
+            self.0.shutdown()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_set_min_level() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .body("DEBUG [flexi_logger_datadog::tests] boosted");
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_min_level(LevelFilter::Info);
+        let (adapter, handle) = spawn_tokio_logger(dd_config.build(), None).await?;
+        let adapter = Arc::new(adapter);
+        // The only test setting the global logger, limited to its own target
+        let logger = flexi_logger::Logger::try_with_str("off, set_min_level=debug")?
+            .log_to_writer(Box::new(SharedAdapter(adapter.clone())))
+            .start()?;
+        // As set by a logger started at the adapter's level
+        log::set_max_level(adapter.max_log_level());
+
+        log::debug!(target: "set_min_level", "filtered");
+        adapter.set_min_level(LevelFilter::Debug);
+        assert_eq!(adapter.max_log_level(), LevelFilter::Debug);
+        log::debug!(target: "set_min_level", "boosted");
+        adapter.flush()?;
+        mock.assert();
+
+        std::mem::drop(logger);
+        std::mem::drop(adapter);
+        handle.await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_proxy() {
+        let config = dd_config("http://localhost".to_string())
+            .with_proxy(Some("not a proxy url".to_string()))
+            .build();
+        assert!(matches!(
+            new_datadog_http_logger(config, None),
+            Err(Error::HttpError(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_proxy() -> Result<()> {
+        let proxy = MockServer::start();
+        // "user:secret" in base64
+        let mock = proxy.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .header("proxy-authorization", "Basic dXNlcjpzZWNyZXQ=")
+                .body("DEBUG [] this is a test");
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config("http://intake.datadog.invalid".to_string());
+        dd_config
+            .with_proxy(Some(proxy.base_url()))
+            .with_proxy_credentials(Some(("user".to_string(), "secret".to_string())));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_local_tee() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["DEBUG [] this is a test"]);
+        let path = std::env::temp_dir().join(format!("dd-tee-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_local_tee(path.clone(), 1024);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        assert_eq!(std::fs::read_to_string(&path)?, "DEBUG [] this is a test\n");
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_fallback_file() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(500);
+        });
+        let path = std::env::temp_dir().join(format!("dd-fallback-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_max_retries(0)
+            .with_fallback_file(Some(path.clone()));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            for line in ["first", "second"] {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Info, format_args!("{}", line)),
+                )?;
+            }
+            assert!(logger.flush_lines().is_err());
+            // The lines were moved to the file, so there is nothing left to retry
+            assert_eq!(logger.flush_lines().ok(), Some(0));
+            mock.assert_hits(1);
+            Ok(())
+        })
+        .await?
+        .await?;
+        assert_eq!(
+            std::fs::read_to_string(&path)?,
+            "INFO [] first\nINFO [] second\n"
+        );
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_circuit_breaker() -> Result<()> {
+        let server = MockServer::start();
+        let mut failure = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(500);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config
+            .with_max_retries(1)
+            .with_retry_base_delay(Duration::milliseconds(1))
+            .with_circuit_breaker(2, Duration::milliseconds(500));
+        let (adapter, mut writer) = new_datadog_http_logger(dd_config.build(), None)?;
+        let handle = tokio::spawn(async move { writer.poll().await });
+
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Info, format_args!("first")),
+        )?;
+        assert!(adapter.flush_lines().is_err());
+        assert!(!adapter.stats().circuit_open());
+        assert!(adapter.flush_lines().is_err());
+        failure.assert_hits(4);
+        assert!(adapter.stats().circuit_open());
+
+        // No requests are made while the circuit is open
+        let error = adapter
+            .flush_blocking(None)
+            .expect_err("circuit should be open");
+        assert!(matches!(error, Error::CircuitOpen));
+        failure.assert_hits(4);
+
+        // A failed probe is a single request that pauses sending again
+        sleep(time::Duration::from_millis(600));
+        assert!(adapter.flush_lines().is_err());
+        failure.assert_hits(5);
+        assert!(adapter.flush_lines().is_err());
+        failure.assert_hits(5);
+
+        failure.delete();
+        let success = mock(&server, vec!["INFO [] first"]);
+        sleep(time::Duration::from_millis(600));
+        assert_eq!(adapter.flush_lines().ok(), Some(1));
+        success.assert();
+        assert_eq!(
+            (
+                adapter.stats().circuit_open(),
+                adapter.stats().circuit_trips()
+            ),
+            (false, 1)
+        );
+        std::mem::drop(adapter);
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_on_error() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(500);
+        });
+        let errors = Arc::new(Mutex::new(vec![]));
+
+        let mut dd_config = dd_config(server.base_url());
+        let on_error = errors.clone();
+        dd_config
+            .with_max_retries(0)
+            .with_on_error(move |e| on_error.lock().unwrap().push(e.to_string()));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("first")),
+            )?;
+            assert!(logger.flush().is_err());
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        let errors = errors.lock().unwrap();
+        // The failed flush, the failed flush on shutdown and the unsent line
+        assert!(errors.len() >= 2);
+        assert!(errors
+            .last()
+            .is_some_and(|e| e.contains("Shutting down without sending 1 log lines")));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_error() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(403)
+                .header("content-type", "application/json")
+                .body(r#"{"errors":["Forbidden"]}"#);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_max_retries(0);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Info, format_args!("first")),
+            )?;
+            match logger.flush_blocking(None) {
+                Err(Error::ApiError { status, body }) => {
+                    assert_eq!(status, 403);
+                    assert_eq!(body, r#"{"errors":["Forbidden"]}"#);
+                }
+                other => panic!("expected an api error, got {:?}", other),
+            }
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_replay_file() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["first line", "second line"]);
+        let path = std::env::temp_dir().join(format!("dd-replay-{}.log", std::process::id()));
+        std::fs::write(&path, "first line\n\nsecond line\n")?;
+
+        let delivered = replay_file(dd_config(server.base_url()).build(), &path).await?;
+        assert_eq!(delivered, 2);
+        mock.assert();
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_negotiated_compression() -> Result<()> {
+        let server = MockServer::start();
+        let options = server.mock(|when, then| {
+            when.method(httpmock::Method::OPTIONS);
+            then.status(200).header("accept-encoding", "deflate, gzip");
+        });
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .header("content-encoding", "gzip")
+                .matches(|req| gunzip(req) == "DEBUG [] this is a test");
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_negotiated_compression(true);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            for _ in 0..2 {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("this is a test")),
+                )?;
+                logger.flush()?;
+            }
+            options.assert_hits(1);
+            mock.assert_hits(2);
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_zstd_compression() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .header("content-encoding", "zstd")
+                .matches(|req| {
+                    let body = req.body.as_deref().unwrap_or_default();
+                    zstd::bulk::decompress(body, MAX_PAYLOAD_BYTES)
+                        .ok()
+                        .as_deref()
+                        == Some("INFO [] first\nINFO [] second".as_bytes())
+                });
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_compression(crate::config::Compression::Zstd);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            for line in ["first", "second"] {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Info, format_args!("{}", line)),
+                )?;
+            }
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_tags() {
+        let mut dd_config = dd_config("http://localhost".to_string());
+        dd_config
+            .with_tags(vec![("a", "1"), ("b", "2"), ("c", "3")])
+            .with_max_tags(Some(2));
+        assert!(matches!(dd_config.try_build(), Err(Error::ConfigError(_))));
+        assert_eq!(
+            dd_config.build().tags,
+            vec![
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "2".to_string())
+            ]
+        );
+        dd_config.with_max_tags(Some(3));
+        assert_eq!(dd_config.try_build().map(|c| c.tags.len()).ok(), Some(3));
+    }
+
+    #[test]
+    fn test_sanitized_tags() {
+        let mut dd_config = dd_config("http://localhost".to_string());
+        dd_config.with_tags(vec![("path", "/a,/b"), ("Team Name", "Core")]);
+        assert_eq!(
+            dd_config.build().tags,
+            vec![
+                ("path".to_string(), "/a_/b".to_string()),
+                ("team_name".to_string(), "core".to_string())
+            ]
+        );
+        assert!(dd_config.try_build().is_ok());
+        dd_config.with_strict_tags(true);
+        assert!(matches!(dd_config.try_build(), Err(Error::ConfigError(_))));
+        dd_config.with_tags(vec![("path", "/a/b")]);
+        assert!(dd_config.try_build().is_ok());
+    }
+
+    #[test]
+    fn test_compression_level() {
+        let mut dd_config = dd_config("http://localhost".to_string());
+        dd_config.with_compression_level(10);
+        assert!(matches!(dd_config.try_build(), Err(Error::ConfigError(_))));
+        assert_eq!(dd_config.build().compression_level, 9);
+        dd_config.with_compression_level(1);
+        assert_eq!(
+            dd_config.try_build().map(|c| c.compression_level).ok(),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut dd_config = dd_config("http://localhost".to_string());
+        dd_config.with_limits(DataDogLimits::conservative());
+        let config = dd_config
+            .try_build()
+            .expect("conservative limits are consistent");
+        assert_eq!(config.max_log_lines, 100);
+        assert_eq!(config.max_line_size, Some(64 * 1024));
+        assert_eq!(config.max_payload_size, 1000000);
+        assert!(DataDogLimits::datadog_defaults().validate().is_ok());
+
+        dd_config.with_limits(DataDogLimits {
+            max_line_size: 2000000,
+            ..DataDogLimits::conservative()
+        });
+        assert!(matches!(dd_config.try_build(), Err(Error::ConfigError(_))));
+        dd_config
+            .with_limits(DataDogLimits::datadog_defaults())
+            .with_max_payload_size(Some(1000));
+        assert!(matches!(dd_config.try_build(), Err(Error::ConfigError(_))));
+        dd_config.with_max_line_size(None);
+        assert!(dd_config.try_build().is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_auth_failure_shutdown() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(403);
+        });
+
+        with_logger(
+            dd_config(server.base_url()).build(),
+            None,
+            |logger| async move {
+                for _ in 0..5 {
+                    logger.write(
+                        &mut DeferredNow::new(),
+                        &record(Level::Debug, format_args!("this is a test")),
+                    )?;
+                    let _ = logger.flush();
+                }
+                mock.assert_hits(3);
+                // The 3 lines of the failed requests and the 2 after them
+                assert_eq!(logger.stats().dropped_lines(), 5);
+                assert_eq!(
+                    logger.stats().dropped_bytes(),
+                    5 * "DEBUG [] this is a test".len()
+                );
+                Ok(())
+            },
+        )
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_auth_failure_fallback_file() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(403);
+        });
+        let path =
+            std::env::temp_dir().join(format!("dd-auth-fallback-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_fallback_file(Some(path.clone()));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            for i in 1..=5 {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("line {}", i)),
+                )?;
+                let _ = logger.flush();
+            }
+            mock.assert_hits(3);
+            // Failed requests already moved their lines to the file
+            assert_eq!(logger.stats().dropped_lines(), 2);
+            Ok(())
+        })
+        .await?
+        .await?;
+        assert_eq!(
+            std::fs::read_to_string(&path)?,
+            (1..=5).map(|i| format!("DEBUG [] line {}\n", i)).join("")
+        );
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_resolve() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["DEBUG [] this is a test"]);
+
+        let mut dd_config = dd_config(format!("http://datadog.invalid:{}", server.port()));
+        dd_config.with_resolve("datadog.invalid", *server.address());
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_internal_records_dropped() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["DEBUG [] this is a test"]);
+
+        with_logger(
+            dd_config(server.base_url()).build(),
+            None,
+            |logger| async move {
+                for target in ["flexi_logger_datadog", "flexi_logger_datadog::writer"] {
+                    logger.write(
+                        &mut DeferredNow::new(),
+                        &Record::builder()
+                            .level(Level::Error)
+                            .target(target)
+                            .args(format_args!("internal error"))
+                            .build(),
+                    )?;
+                }
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("this is a test")),
+                )?;
+                logger.flush()?;
+                mock.assert();
+                Ok(())
+            },
+        )
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_flush_on_target() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["DEBUG [] this is a test", "INFO [] payment"]);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_flush_on_target(vec!["payments"]);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            )?;
+            logger.write(
+                &mut DeferredNow::new(),
+                &Record::builder()
+                    .level(Level::Info)
+                    .target("payments::charge")
+                    .args(format_args!("payment"))
+                    .build(),
+            )?;
+            sleep(time::Duration::from_millis(500));
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_flush_waits_for_requests() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200).delay(time::Duration::from_millis(200));
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_max_log_lines(Some(1));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            for i in 0..3 {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("this is a test {}", i)),
+                )?;
+            }
+            logger.flush()?;
+            mock.assert_hits(3);
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_sort_batches() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["DEBUG [] first", "DEBUG [] second"]);
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_sort_batches(true);
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            let mut first = DeferredNow::new();
+            first.now();
+            sleep(time::Duration::from_millis(10));
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("second")),
+            )?;
+            logger.write(&mut first, &record(Level::Debug, format_args!("first")))?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_flush_latency_callback() -> Result<()> {
+        let server = MockServer::start();
+        let mock = mock(&server, vec!["DEBUG [] this is a test"]);
+        let latencies = Arc::new(Mutex::new(vec![]));
+
+        let mut dd_config = dd_config(server.base_url());
+        let recorded = latencies.clone();
+        dd_config.with_flush_latency_callback(move |d| recorded.lock().unwrap().push(d));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test")),
+            )?;
+            logger.flush()?;
+            mock.assert();
+            Ok(())
+        })
+        .await?
+        .await?;
+        let latencies = latencies.lock().unwrap();
+        assert_eq!(latencies.len(), 1);
+        assert!(latencies[0] > time::Duration::ZERO && latencies[0] < time::Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_min_flush_interval() -> Result<()> {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200);
+        });
+
+        let mut dd_config = dd_config(server.base_url());
+        dd_config.with_min_flush_interval(Some(Duration::milliseconds(500)));
+
+        with_logger(dd_config.build(), None, |logger| async move {
+            logger.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("this is a test 0")),
+            )?;
+            assert_eq!(logger.flush_lines()?, 1);
+            let started = time::Instant::now();
+            for i in 1..=3 {
+                logger.write(
+                    &mut DeferredNow::new(),
+                    &record(Level::Debug, format_args!("this is a test {}", i)),
+                )?;
+            }
+            // Too soon after the last flush, so this waits for the deferred flush of its lines
+            mock.assert_hits(1);
+            assert_eq!(logger.flush_lines()?, 3);
+            assert!(started.elapsed() >= time::Duration::from_millis(400));
+            mock.assert_hits(2);
+            Ok(())
+        })
+        .await?
+        .await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "tcp")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_tcp_writer() -> Result<()> {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?.to_string();
+        let (adapter, mut writer) = crate::new_datadog_tcp_logger(
+            dd_config("http://localhost".to_string()).build(),
+            address,
+            None,
+        )?;
+        let handle = tokio::spawn(async move { writer.poll().await });
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Debug, format_args!("this is a test")),
+        )?;
+        adapter.flush()?;
+
+        let (stream, _) = listener.accept()?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        assert_eq!(line, "dummy_key DEBUG [] this is a test\n");
+        assert_eq!(adapter.stats().sent_lines(), 1);
+
+        // The adapter's flush signal reaches the writer too
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Debug, format_args!("signalled")),
+        )?;
+        adapter.request_flush()?;
+        line.clear();
+        reader.read_line(&mut line)?;
+        assert_eq!(line, "dummy_key DEBUG [] signalled\n");
+        drop(adapter);
+        handle.await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "tcp")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_tcp_writer_json() -> Result<()> {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?.to_string();
+        let mut dd_config = dd_config("http://localhost".to_string());
+        dd_config
+            .with_payload_format(PayloadFormat::Json)
+            .with_max_line_size(Some(8));
+        let (adapter, mut writer) =
+            crate::new_datadog_tcp_logger(dd_config.build(), address, None)?;
+        let handle = tokio::spawn(async move { writer.poll().await });
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Debug, format_args!("this is a test")),
+        )?;
+        adapter.flush()?;
+
+        let (stream, _) = listener.accept()?;
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        let (api_key, log) = line.trim_end().split_once(' ').unwrap();
+        assert_eq!(api_key, "dummy_key");
+        let log: serde_json::Value = serde_json::from_str(log)?;
+        assert_eq!(log["service"], "test");
+        // Options applied by the shared writer loop apply to TCP too
+        assert_eq!(log["message"], "DEBUG []");
+        drop(adapter);
+        handle.await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "tcp")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_tcp_writer_unreachable() -> Result<()> {
+        // Nothing listens on a port once its listener is dropped
+        let address = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .to_string();
+        let mut dd_config = dd_config("http://localhost".to_string());
+        dd_config.with_max_buffer_bytes(Some(40));
+        let (adapter, mut writer) =
+            crate::new_datadog_tcp_logger(dd_config.build(), address, None)?;
+        let handle = tokio::spawn(async move { writer.poll().await });
+        for i in 1..=5 {
+            adapter.write(
+                &mut DeferredNow::new(),
+                &record(Level::Debug, format_args!("line {}", i)),
+            )?;
+            assert!(adapter.flush().is_err());
+        }
+        assert_eq!(adapter.stats().failed_batches(), 5);
+        assert_eq!(adapter.stats().evicted_lines(), 3);
+        drop(adapter);
+        handle.await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "tcp")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_tcp_writer_retries() -> Result<()> {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .to_string();
+        let mut dd_config = dd_config("http://localhost".to_string());
+        dd_config
+            .with_max_retries(2)
+            .with_retry_base_delay(Duration::milliseconds(10));
+        let (adapter, mut writer) =
+            crate::new_datadog_tcp_logger(dd_config.build(), address, None)?;
+        let handle = tokio::spawn(async move { writer.poll().await });
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Debug, format_args!("this is a test")),
+        )?;
+        assert!(adapter.flush().is_err());
+        assert_eq!(adapter.stats().retries(), 2);
+        assert_eq!(adapter.stats().failed_batches(), 1);
+        drop(adapter);
+        handle.await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "tcp")]
+    #[test]
+    fn test_tcp_http_only_options() {
+        let mut dd_config = dd_config("http://localhost".to_string());
+        dd_config
+            .with_proxy(Some("http://proxy.invalid".to_string()))
+            .with_gzip(GzipMode::Always)
+            .with_payload_format(PayloadFormat::OtlpJson);
+        let error =
+            crate::new_datadog_tcp_logger(dd_config.build(), "127.0.0.1:10514".to_string(), None)
+                .err();
+        assert!(matches!(
+            error,
+            Some(Error::ConfigError(e))
+                if e.ends_with("support proxy, compression, OTLP payloads")
+        ));
+    }
+
+    #[cfg(feature = "tcp-tls")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_tcp_tls() -> Result<()> {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?.to_string();
+        // Read the start of the TLS handshake, then hang up so it fails
+        let server = std::thread::spawn(move || -> std::io::Result<u8> {
+            let (mut stream, _) = listener.accept()?;
+            let mut record_type = [0; 1];
+            stream.read_exact(&mut record_type)?;
+            Ok(record_type[0])
+        });
+        let mut dd_config = dd_config("http://localhost".to_string());
+        dd_config.with_tcp_tls(true);
+        let (adapter, mut writer) =
+            crate::new_datadog_tcp_logger(dd_config.build(), address, None)?;
+        let handle = tokio::spawn(async move { writer.poll().await });
+        adapter.write(
+            &mut DeferredNow::new(),
+            &record(Level::Debug, format_args!("this is a test")),
+        )?;
+        assert!(adapter.flush().is_err());
+        // 0x16 is a TLS handshake record
+        assert_eq!(server.join().expect("server thread panicked")?, 0x16);
+        drop(adapter);
+        handle.await?;
+        Ok(())
+    }
 }
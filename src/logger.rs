@@ -0,0 +1,118 @@
+//! Handle pairing a spawned writer task with whatever shuts its adapter down
+
+use crate::adapter::DataDogAdapter;
+use crate::config::DataDogConfigBuilder;
+use crate::error::Error;
+use crate::new_adapter;
+use crate::stats::WriterStats;
+use crate::writer::DataDogHttpWriter;
+use chrono::Duration;
+use flexi_logger::LoggerHandle;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+/// What closes the adapter's channels so the writer can finish
+enum Closer {
+    /// Adapter owned by this handle, boxed as it is much larger than a [`LoggerHandle`]
+    Adapter(Box<DataDogAdapter>),
+    /// Adapter installed as the global logger's writer
+    Logger(LoggerHandle),
+}
+
+/// Running logger whose writer task is spawned on the tokio runtime.
+///
+/// [`Self::shutdown`] performs the full shutdown sequence: the adapter flushes and closes its
+/// channels, then the writer task is awaited until it has sent its final flush
+pub struct DataDogLogger {
+    /// Closes the adapter
+    closer: Closer,
+    /// Writer task
+    join: JoinHandle<()>,
+    /// Counters shared with the writer
+    stats: Arc<WriterStats>,
+}
+
+impl DataDogLogger {
+    /// Build the configuration, check the api is reachable with the configured api key, and
+    /// spawn the writer on the tokio runtime, failing if any step fails
+    pub async fn connect(
+        datadog_config: &DataDogConfigBuilder,
+        flush_interval: Option<Duration>,
+    ) -> Result<Self, Error> {
+        let datadog_config = datadog_config.try_build()?;
+        let (adapter, channels) = new_adapter(&datadog_config);
+        let mut writer =
+            DataDogHttpWriter::from_channels(datadog_config, flush_interval, channels)?;
+        if let Err(e) = writer.ping().await {
+            // Drop the writer first, so the adapter's final flush finds it gone rather than
+            // waiting on a writer that isn't polling
+            std::mem::drop(writer);
+            return Err(e);
+        }
+        let join = tokio::spawn(async move { writer.poll().await });
+        Ok(Self::from_adapter(adapter, join))
+    }
+
+    /// Create [`DataDogLogger`] owning `adapter`
+    pub(crate) fn from_adapter(adapter: DataDogAdapter, join: JoinHandle<()>) -> Self {
+        Self {
+            stats: adapter.stats(),
+            closer: Closer::Adapter(Box::new(adapter)),
+            join,
+        }
+    }
+
+    /// Create [`DataDogLogger`] for an adapter installed as the global logger's writer
+    pub(crate) fn from_logger(
+        handle: LoggerHandle,
+        stats: Arc<WriterStats>,
+        join: JoinHandle<()>,
+    ) -> Self {
+        Self {
+            closer: Closer::Logger(handle),
+            join,
+            stats,
+        }
+    }
+
+    /// Adapter, unless it has been installed as the global logger's writer
+    pub fn adapter(&self) -> Option<&DataDogAdapter> {
+        match &self.closer {
+            Closer::Adapter(adapter) => Some(adapter.as_ref()),
+            Closer::Logger(_) => None,
+        }
+    }
+
+    /// Global logger handle, if the adapter has been installed as its writer
+    pub fn logger_handle(&self) -> Option<&LoggerHandle> {
+        match &self.closer {
+            Closer::Adapter(_) => None,
+            Closer::Logger(handle) => Some(handle),
+        }
+    }
+
+    /// Counters describing the writer
+    pub fn stats(&self) -> Arc<WriterStats> {
+        self.stats.clone()
+    }
+
+    /// Shut down the adapter, wait for the writer task to drain its channel and finish, and
+    /// return its final counters. Fails with [`Error::FlushError`] if the final flush left lines
+    /// unsent.
+    ///
+    /// Dropping the adapter still flushes, but can't wait for the writer to finish
+    pub async fn shutdown(self) -> Result<Arc<WriterStats>, Error> {
+        match self.closer {
+            Closer::Adapter(adapter) => std::mem::drop(adapter),
+            Closer::Logger(handle) => handle.shutdown(),
+        }
+        self.join.await?;
+        match self.stats.unsent_lines() {
+            0 => Ok(self.stats),
+            unsent => Err(Error::FlushError(format!(
+                "Final flush left {} log lines unsent",
+                unsent
+            ))),
+        }
+    }
+}
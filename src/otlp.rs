@@ -0,0 +1,94 @@
+//! OpenTelemetry (OTLP/JSON) encoding of log lines, for sending through an OpenTelemetry collector
+
+use crate::event::LogEvent;
+use log::Level;
+use serde_json::{json, Map, Value};
+
+/// OTLP resource describing where every log line comes from
+pub(crate) fn resource(hostname: &str, service: &str, source: &str, tags: &str) -> Value {
+    json!({
+        "attributes": [
+            attribute("host.name", &hostname.into()),
+            attribute("service.name", &service.into()),
+            attribute("ddsource", &source.into()),
+            attribute("ddtags", &tags.into()),
+        ]
+    })
+}
+
+/// Serialize lines as an OTLP/JSON `ExportLogsServiceRequest` under `resource`
+pub(crate) fn serialize(lines: &[LogEvent], resource: &Value) -> Value {
+    let records = lines
+        .iter()
+        .map(|e| {
+            let mut attributes = e.attributes.clone();
+            if let Some(source) = &e.source {
+                attributes.insert("ddsource".to_string(), source.as_str().into());
+            }
+            if let Some(service) = &e.service {
+                attributes.insert("service".to_string(), service.as_str().into());
+            }
+            if let Some(tags) = &e.tags {
+                attributes.insert("ddtags".to_string(), tags.as_str().into());
+            }
+            let mut record = Map::new();
+            record.insert(
+                "timeUnixNano".to_string(),
+                e.timestamp
+                    .timestamp_nanos_opt()
+                    .unwrap_or_default()
+                    .to_string()
+                    .into(),
+            );
+            if let Some(level) = e.level {
+                record.insert("severityNumber".to_string(), severity_number(level).into());
+                record.insert("severityText".to_string(), level.as_str().into());
+            }
+            record.insert("body".to_string(), any_value(&e.line.as_str().into()));
+            record.insert(
+                "attributes".to_string(),
+                attributes
+                    .iter()
+                    .map(|(key, value)| attribute(key, value))
+                    .collect(),
+            );
+            Value::Object(record)
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "resourceLogs": [{
+            "resource": resource,
+            "scopeLogs": [{
+                "scope": { "name": env!("CARGO_PKG_NAME") },
+                "logRecords": records,
+            }],
+        }],
+    })
+}
+
+/// OTLP `SeverityNumber` of the lowest severity in each level's range
+fn severity_number(level: Level) -> u8 {
+    match level {
+        Level::Trace => 1,
+        Level::Debug => 5,
+        Level::Info => 9,
+        Level::Warn => 13,
+        Level::Error => 17,
+    }
+}
+
+/// OTLP `KeyValue`
+fn attribute(key: &str, value: &Value) -> Value {
+    json!({ "key": key, "value": any_value(value) })
+}
+
+/// OTLP `AnyValue`. 64 bit integers are strings in OTLP/JSON
+fn any_value(value: &Value) -> Value {
+    match value {
+        Value::String(s) => json!({ "stringValue": s }),
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_f64() => json!({ "doubleValue": n }),
+        Value::Number(n) => json!({ "intValue": n.to_string() }),
+        value => json!({ "stringValue": value.to_string() }),
+    }
+}
@@ -0,0 +1,68 @@
+//! Recorder of every log line handed to a writer, for asserting on shipped logs in tests.
+//!
+//! Only intended for tests. Lines are recorded once they reach the writer, so call `flush()` on
+//! the logger before asserting. The recorder is global, so tests running in parallel see each
+//! other's lines; assert on content unique to the test or [`Recorder::clear`] it first.
+//!
+//! ```no_run
+//! use flexi_logger_datadog::recorder::recorder;
+//!
+//! // ... log and flush ...
+//! recorder().assert_contains("expected message");
+//! ```
+
+use std::sync::{Mutex, MutexGuard};
+
+/// Global recorder written to by every writer
+static RECORDER: Recorder = Recorder {
+    lines: Mutex::new(Vec::new()),
+};
+
+/// Get the global [`Recorder`]
+pub fn recorder() -> &'static Recorder {
+    &RECORDER
+}
+
+/// Lines handed to writers
+pub struct Recorder {
+    /// Recorded lines in the order they were received
+    lines: Mutex<Vec<String>>,
+}
+
+impl Recorder {
+    /// Lock the recorded lines, ignoring poisoning from panicking assertions
+    fn lock(&self) -> MutexGuard<'_, Vec<String>> {
+        self.lines.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Record a line
+    pub(crate) fn record(&self, line: &str) {
+        self.lock().push(line.to_string());
+    }
+
+    /// All recorded lines
+    pub fn lines(&self) -> Vec<String> {
+        self.lock().clone()
+    }
+
+    /// Remove all recorded lines
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// Whether any recorded line contains `needle`
+    pub fn contains(&self, needle: &str) -> bool {
+        self.lock().iter().any(|l| l.contains(needle))
+    }
+
+    /// Panic unless a recorded line contains `needle`
+    pub fn assert_contains(&self, needle: &str) {
+        let lines = self.lines();
+        assert!(
+            lines.iter().any(|l| l.contains(needle)),
+            "No recorded log line contains {:?}, recorded lines: {:#?}",
+            needle,
+            lines
+        );
+    }
+}
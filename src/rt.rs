@@ -0,0 +1,124 @@
+//! Timers for the writer task, from whichever async runtime drives it
+
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+use flume::RecvTimeoutError;
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+use std::cmp::Ordering;
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+use std::collections::binary_heap::{BinaryHeap, PeekMut};
+use std::future::{self, Future};
+use std::pin::pin;
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+use std::sync::OnceLock;
+use std::task::Poll;
+use std::time::Duration;
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+use std::time::Instant;
+
+/// Wait for `duration` without blocking the thread
+#[cfg(feature = "tokio")]
+pub(crate) async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+
+/// Wait for `duration` without blocking the thread
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+pub(crate) async fn sleep(duration: Duration) {
+    async_std::task::sleep(duration).await
+}
+
+/// Timer registered with the shared timer thread
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+struct Timer {
+    /// When the timer fires
+    deadline: Instant,
+    /// Woken when the timer fires, disconnected if the sleep was dropped first
+    wake: flume::Sender<()>,
+}
+
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+impl Eq for Timer {}
+
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+impl Ord for Timer {
+    /// Earliest deadline first, so the heap of timers pops the next to fire
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+/// Channel registering timers with the shared timer thread, started on first use
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+fn timers() -> &'static flume::Sender<Timer> {
+    static TIMERS: OnceLock<flume::Sender<Timer>> = OnceLock::new();
+    TIMERS.get_or_init(|| {
+        let (sender, receiver) = flume::unbounded();
+        std::thread::Builder::new()
+            .name("flexi-logger-datadog-timer".to_string())
+            .spawn(move || run_timers(receiver))
+            .expect("failed to spawn timer thread");
+        sender
+    })
+}
+
+/// Fire timers as their deadlines pass, forgetting timers whose sleep was dropped
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+fn run_timers(receiver: flume::Receiver<Timer>) {
+    let mut timers = BinaryHeap::<Timer>::new();
+    loop {
+        let now = Instant::now();
+        while let Some(timer) = timers.peek_mut().filter(|t| t.deadline <= now) {
+            let _ = PeekMut::pop(timer).wake.send(());
+        }
+        let registered = match timers.peek() {
+            Some(next) => receiver.recv_deadline(next.deadline),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match registered {
+            Ok(timer) => {
+                timers.retain(|t| !t.wake.is_disconnected());
+                timers.push(timer);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// Wait for `duration` without blocking the thread. Without a runtime feature there is no
+/// timer to register with, so a single shared thread wakes the task once `duration` has passed
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+pub(crate) async fn sleep(duration: Duration) {
+    let (wake, woken) = flume::bounded(1);
+    match Instant::now().checked_add(duration) {
+        Some(deadline) if timers().send(Timer { deadline, wake }).is_ok() => {
+            let _ = woken.recv_async().await;
+        }
+        _ => future::pending().await,
+    }
+}
+
+/// Run `future` for at most `duration`, [`None`] if it didn't complete in time
+pub(crate) async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    let mut future = pin!(future);
+    let mut timer = pin!(sleep(duration));
+    future::poll_fn(|cx| match future.as_mut().poll(cx) {
+        Poll::Ready(output) => Poll::Ready(Some(output)),
+        Poll::Pending => timer.as_mut().poll(cx).map(|_| None),
+    })
+    .await
+}
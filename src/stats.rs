@@ -0,0 +1,319 @@
+//! Counters describing what the writer has done, shared between the writer and adapter
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Writer counters, readable from [`crate::adapter::DataDogAdapter::stats`] while the writer runs
+#[derive(Debug, Default)]
+pub struct WriterStats {
+    /// Lines evicted from the buffer because it exceeded the maximum buffer size
+    evicted_lines: AtomicUsize,
+    /// Bytes evicted from the buffer because it exceeded the maximum buffer size
+    evicted_bytes: AtomicUsize,
+    /// Lines received from the adapter
+    received_lines: AtomicUsize,
+    /// Total time received lines spent in the channel, in microseconds
+    channel_dwell_total_us: AtomicU64,
+    /// Longest time a received line spent in the channel, in microseconds
+    channel_dwell_max_us: AtomicU64,
+    /// Lines dropped because they spent too long in the channel
+    stale_lines: AtomicUsize,
+    /// Lines delivered to DataDog
+    sent_lines: AtomicUsize,
+    /// Request body bytes delivered to DataDog, after compression
+    sent_bytes: AtomicUsize,
+    /// Requests retried after a retryable failure
+    retries: AtomicUsize,
+    /// Requests that failed after exhausting their retries
+    failed_batches: AtomicUsize,
+    /// Lines dropped because a single line exceeded the DataDog request limit
+    oversized_lines: AtomicUsize,
+    /// Lines dropped because the channel between the adapter and writer was full
+    overflow_lines: AtomicUsize,
+    /// Whether sending is paused by the circuit breaker
+    circuit_open: AtomicBool,
+    /// Times the circuit breaker opened
+    circuit_trips: AtomicUsize,
+    /// Lines still buffered when the writer shut down
+    unsent_lines: AtomicUsize,
+    /// Lines currently held in the writer's buffer
+    buffered_lines: AtomicUsize,
+    /// Bytes of log lines currently held in the writer's buffer
+    buffered_bytes: AtomicUsize,
+    /// Times the channel between the adapter and writer filled past its high-water mark
+    high_water_crossings: AtomicUsize,
+    /// Lines dropped because sending was disabled after repeated authentication failures
+    dropped_lines: AtomicUsize,
+    /// Bytes dropped because sending was disabled after repeated authentication failures
+    dropped_bytes: AtomicUsize,
+}
+
+/// Point in time copy of the [`WriterStats`] counters, e.g. for exporting as metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriterStatsSnapshot {
+    /// Lines received from the adapter
+    pub received_lines: usize,
+    /// Lines delivered to DataDog
+    pub sent_lines: usize,
+    /// Request body bytes delivered to DataDog, after compression
+    pub sent_bytes: usize,
+    /// Requests retried after a retryable failure
+    pub retries: usize,
+    /// Requests that failed after exhausting their retries
+    pub failed_batches: usize,
+    /// Lines dropped because a single line exceeded the DataDog request limit
+    pub oversized_lines: usize,
+    /// Lines evicted from the buffer because it exceeded the maximum buffer size
+    pub evicted_lines: usize,
+    /// Bytes evicted from the buffer because it exceeded the maximum buffer size
+    pub evicted_bytes: usize,
+    /// Lines dropped because they spent too long in the channel
+    pub stale_lines: usize,
+    /// Lines dropped because the channel between the adapter and writer was full
+    pub overflow_lines: usize,
+    /// Whether sending is paused by the circuit breaker
+    pub circuit_open: bool,
+    /// Times the circuit breaker opened
+    pub circuit_trips: usize,
+    /// Lines still buffered when the writer shut down
+    pub unsent_lines: usize,
+    /// Lines currently held in the writer's buffer
+    pub buffered_lines: usize,
+    /// Bytes of log lines currently held in the writer's buffer
+    pub buffered_bytes: usize,
+    /// Times the channel between the adapter and writer filled past its high-water mark
+    pub high_water_crossings: usize,
+    /// Lines dropped because sending was disabled after repeated authentication failures
+    pub dropped_lines: usize,
+    /// Bytes dropped because sending was disabled after repeated authentication failures
+    pub dropped_bytes: usize,
+}
+
+/// How far behind the writer is, e.g. for a health check. A depth that keeps growing means
+/// DataDog is unreachable or the writer can't keep up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferDepth {
+    /// Lines waiting in the channel between the adapter and writer
+    pub queued_lines: usize,
+    /// Lines held in the writer's buffer
+    pub buffered_lines: usize,
+    /// Bytes of log lines held in the writer's buffer
+    pub buffered_bytes: usize,
+}
+
+impl WriterStats {
+    /// Lines evicted from the buffer because it exceeded the maximum buffer size
+    pub fn evicted_lines(&self) -> usize {
+        self.evicted_lines.load(Ordering::Relaxed)
+    }
+
+    /// Bytes evicted from the buffer because it exceeded the maximum buffer size
+    pub fn evicted_bytes(&self) -> usize {
+        self.evicted_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Lines received from the adapter
+    pub fn received_lines(&self) -> usize {
+        self.received_lines.load(Ordering::Relaxed)
+    }
+
+    /// Longest time a line spent in the channel between the adapter and writer
+    pub fn max_channel_dwell(&self) -> Duration {
+        Duration::from_micros(self.channel_dwell_max_us.load(Ordering::Relaxed))
+    }
+
+    /// Mean time lines spent in the channel between the adapter and writer
+    pub fn mean_channel_dwell(&self) -> Duration {
+        match self.received_lines() as u64 {
+            0 => Duration::ZERO,
+            count => {
+                Duration::from_micros(self.channel_dwell_total_us.load(Ordering::Relaxed) / count)
+            }
+        }
+    }
+
+    /// Lines dropped because they spent too long in the channel
+    pub fn stale_lines(&self) -> usize {
+        self.stale_lines.load(Ordering::Relaxed)
+    }
+
+    /// Lines delivered to DataDog
+    pub fn sent_lines(&self) -> usize {
+        self.sent_lines.load(Ordering::Relaxed)
+    }
+
+    /// Request body bytes delivered to DataDog, after compression
+    pub fn sent_bytes(&self) -> usize {
+        self.sent_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Requests retried after a retryable failure
+    pub fn retries(&self) -> usize {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Requests that failed after exhausting their retries
+    pub fn failed_batches(&self) -> usize {
+        self.failed_batches.load(Ordering::Relaxed)
+    }
+
+    /// Lines dropped because a single line exceeded the DataDog request limit
+    pub fn oversized_lines(&self) -> usize {
+        self.oversized_lines.load(Ordering::Relaxed)
+    }
+
+    /// Lines dropped because the channel between the adapter and writer was full
+    pub fn overflow_lines(&self) -> usize {
+        self.overflow_lines.load(Ordering::Relaxed)
+    }
+
+    /// Whether sending is paused by the circuit breaker, including while a probe request is
+    /// being made after the cooldown
+    pub fn circuit_open(&self) -> bool {
+        self.circuit_open.load(Ordering::Relaxed)
+    }
+
+    /// Times the circuit breaker opened
+    pub fn circuit_trips(&self) -> usize {
+        self.circuit_trips.load(Ordering::Relaxed)
+    }
+
+    /// Lines still buffered when the writer shut down, because its final flush failed
+    pub fn unsent_lines(&self) -> usize {
+        self.unsent_lines.load(Ordering::Relaxed)
+    }
+
+    /// Lines currently held in the writer's buffer
+    pub fn buffered_lines(&self) -> usize {
+        self.buffered_lines.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of log lines currently held in the writer's buffer
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Times the channel between the adapter and writer filled past its high-water mark,
+    /// meaning logs are produced faster than they are sent
+    pub fn high_water_crossings(&self) -> usize {
+        self.high_water_crossings.load(Ordering::Relaxed)
+    }
+
+    /// Lines dropped because sending was disabled after repeated authentication failures,
+    /// including those written to the fallback file
+    pub fn dropped_lines(&self) -> usize {
+        self.dropped_lines.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of log lines dropped because sending was disabled after repeated authentication
+    /// failures, including those written to the fallback file
+    pub fn dropped_bytes(&self) -> usize {
+        self.dropped_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Copy of every counter. Counters are read one at a time, so a snapshot taken while the
+    /// writer runs may be mid update
+    pub fn snapshot(&self) -> WriterStatsSnapshot {
+        WriterStatsSnapshot {
+            received_lines: self.received_lines(),
+            sent_lines: self.sent_lines(),
+            sent_bytes: self.sent_bytes(),
+            retries: self.retries(),
+            failed_batches: self.failed_batches(),
+            oversized_lines: self.oversized_lines(),
+            evicted_lines: self.evicted_lines(),
+            evicted_bytes: self.evicted_bytes(),
+            stale_lines: self.stale_lines(),
+            overflow_lines: self.overflow_lines(),
+            circuit_open: self.circuit_open(),
+            circuit_trips: self.circuit_trips(),
+            unsent_lines: self.unsent_lines(),
+            buffered_lines: self.buffered_lines(),
+            buffered_bytes: self.buffered_bytes(),
+            high_water_crossings: self.high_water_crossings(),
+            dropped_lines: self.dropped_lines(),
+            dropped_bytes: self.dropped_bytes(),
+        }
+    }
+
+    /// Count a received line and the time it spent in the channel
+    pub(crate) fn record_received(&self, dwell: Duration) {
+        let dwell_us = dwell.as_micros() as u64;
+        self.received_lines.fetch_add(1, Ordering::Relaxed);
+        self.channel_dwell_total_us
+            .fetch_add(dwell_us, Ordering::Relaxed);
+        self.channel_dwell_max_us
+            .fetch_max(dwell_us, Ordering::Relaxed);
+    }
+
+    /// Count a line dropped for spending too long in the channel
+    pub(crate) fn record_stale(&self) {
+        self.stale_lines.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a delivered request
+    pub(crate) fn record_sent(&self, lines: usize, bytes: usize) {
+        self.sent_lines.fetch_add(lines, Ordering::Relaxed);
+        self.sent_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a retried request
+    pub(crate) fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a request that failed after exhausting its retries
+    pub(crate) fn record_failed_batch(&self) {
+        self.failed_batches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count lines dropped for exceeding the request limit
+    pub(crate) fn record_oversized(&self, lines: usize) {
+        self.oversized_lines.fetch_add(lines, Ordering::Relaxed);
+    }
+
+    /// Count a line dropped because the channel was full
+    pub(crate) fn record_overflow(&self) {
+        self.overflow_lines.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Mark the circuit breaker open, counting a trip if it was closed
+    pub(crate) fn record_circuit_open(&self) {
+        if !self.circuit_open.swap(true, Ordering::Relaxed) {
+            self.circuit_trips.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Mark the circuit breaker closed
+    pub(crate) fn record_circuit_closed(&self) {
+        self.circuit_open.store(false, Ordering::Relaxed);
+    }
+
+    /// Count lines left unsent by the final flush
+    pub(crate) fn record_unsent(&self, lines: usize) {
+        self.unsent_lines.fetch_add(lines, Ordering::Relaxed);
+    }
+
+    /// Update the size of the writer's buffer
+    pub(crate) fn record_buffer(&self, lines: usize, bytes: usize) {
+        self.buffered_lines.store(lines, Ordering::Relaxed);
+        self.buffered_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Count the channel filling past its high-water mark
+    pub(crate) fn record_high_water(&self) {
+        self.high_water_crossings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count evicted lines
+    pub(crate) fn record_eviction(&self, lines: usize, bytes: usize) {
+        self.evicted_lines.fetch_add(lines, Ordering::Relaxed);
+        self.evicted_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count lines dropped because sending was disabled
+    pub(crate) fn record_dropped(&self, lines: usize, bytes: usize) {
+        self.dropped_lines.fetch_add(lines, Ordering::Relaxed);
+        self.dropped_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,256 @@
+//! Writer task that sends logs to the DataDog TCP intake
+
+use crate::config::{ApiKeyProvider, Compression, GzipMode, PayloadFormat, DEFAULT_API_KEY_HEADER};
+use crate::error::Error;
+use crate::error::Error::ConfigError;
+use crate::event::LogEvent;
+use crate::rt;
+use crate::stats::WriterStats;
+use crate::writer::{DataDogHttpWriter, WriterChannels};
+use crate::DataDogConfig;
+use chrono::Duration;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(feature = "tcp-tls")]
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+#[cfg(feature = "tcp-tls")]
+use tokio_rustls::TlsConnector;
+
+/// Bytes of log lines buffered while the TCP intake is unreachable, unless
+/// [`DataDogConfig::max_buffer_bytes`] is set
+pub const DEFAULT_TCP_BUFFER_BYTES: usize = 16 * 1024 * 1024;
+
+/// Open connection to the intake, plain or over TLS
+type Connection = Box<dyn AsyncWrite + Send + Sync + Unpin>;
+
+/// Connection to the DataDog TCP intake, reconnected on the next send after a failure
+pub(crate) struct TcpTransport {
+    /// DataDog TCP intake address, as `host:port`
+    address: String,
+    /// DataDog api key
+    api_key: String,
+    /// Called for the api key of each send, in place of the static key
+    api_key_provider: Option<ApiKeyProvider>,
+    /// Longest connecting or writing a send may take
+    timeout: time::Duration,
+    /// TLS configuration if the intake is reached over TLS
+    #[cfg(feature = "tcp-tls")]
+    tls: Option<Arc<ClientConfig>>,
+    /// Open connection
+    connection: Option<Connection>,
+}
+
+impl TcpTransport {
+    /// Create [`TcpTransport`] to `address`, not connected until the first send
+    fn new(datadog_config: &DataDogConfig, address: String) -> Self {
+        Self {
+            address,
+            api_key: datadog_config.api_key.clone(),
+            api_key_provider: datadog_config.api_key_provider.clone(),
+            timeout: datadog_config.request_timeout.to_std().unwrap_or_default(),
+            #[cfg(feature = "tcp-tls")]
+            tls: datadog_config.tcp_tls.then(Self::tls_config),
+            connection: None,
+        }
+    }
+
+    /// Client configuration trusting the Mozilla root certificates
+    #[cfg(feature = "tcp-tls")]
+    fn tls_config() -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+        Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+    }
+
+    /// Api key each line is prefixed with
+    pub(crate) fn api_key(&self) -> String {
+        match self.api_key_provider.as_ref() {
+            Some(provider) => provider(),
+            None => self.api_key.clone(),
+        }
+    }
+
+    /// Open a connection if there isn't one, within the timeout
+    pub(crate) async fn connect(&mut self) -> Result<&mut Connection, Error> {
+        let connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => rt::timeout(self.timeout, self.open())
+                .await
+                .unwrap_or_else(|| Err(Self::timed_out("connecting")))?,
+        };
+        Ok(self.connection.insert(connection))
+    }
+
+    /// Connect to the intake, completing the TLS handshake if enabled
+    async fn open(&self) -> io::Result<Connection> {
+        let stream = TcpStream::connect(&self.address).await?;
+        #[cfg(feature = "tcp-tls")]
+        if let Some(tls) = self.tls.clone() {
+            let host = self
+                .address
+                .rsplit_once(':')
+                .map_or(self.address.as_str(), |(host, _)| host);
+            let name = ServerName::try_from(host)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+            let stream = TlsConnector::from(tls).connect(name, stream).await?;
+            return Ok(Box::new(stream));
+        }
+        Ok(Box::new(stream))
+    }
+
+    /// Write `payload` to the connection within the timeout, dropping the connection on failure
+    pub(crate) async fn send(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let timeout = self.timeout;
+        let connection = self.connect().await?;
+        let written = rt::timeout(timeout, async {
+            connection.write_all(payload).await?;
+            connection.flush().await
+        })
+        .await
+        .unwrap_or_else(|| Err(Self::timed_out("writing")));
+        if written.is_err() {
+            self.connection = None;
+        }
+        Ok(written?)
+    }
+
+    /// Error for an operation that didn't complete within the timeout
+    fn timed_out(operation: &str) -> io::Error {
+        io::Error::new(
+            ErrorKind::TimedOut,
+            format!("Timed out {} to the DataDog TCP intake", operation),
+        )
+    }
+}
+
+/// Options of `datadog_config` only the HTTP intake supports
+fn http_only_options(datadog_config: &DataDogConfig) -> Vec<&'static str> {
+    let mut options = vec![];
+    if datadog_config.proxy.is_some() || datadog_config.proxy_credentials.is_some() {
+        options.push("proxy");
+    }
+    if datadog_config.client.is_some() {
+        options.push("client");
+    }
+    if datadog_config.client_recycle.is_some() {
+        options.push("client recycling");
+    }
+    if !datadog_config.resolve.is_empty() {
+        options.push("resolve overrides");
+    }
+    if datadog_config.api_key_header != DEFAULT_API_KEY_HEADER
+        || datadog_config.api_key_template.is_some()
+    {
+        options.push("api key header");
+    }
+    let compressed = match datadog_config.compression {
+        Compression::None => false,
+        Compression::Gzip => datadog_config.gzip != GzipMode::Never,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => true,
+    };
+    if compressed || datadog_config.negotiated_compression {
+        options.push("compression");
+    }
+    #[cfg(feature = "zstd")]
+    if datadog_config.zstd_dictionary.is_some() {
+        options.push("zstd dictionary");
+    }
+    #[cfg(feature = "stream")]
+    if datadog_config.streaming_upload {
+        options.push("streaming upload");
+    }
+    if datadog_config.payload_format == PayloadFormat::OtlpJson {
+        options.push("OTLP payloads");
+    }
+    options
+}
+
+/// TCP writer.
+///
+/// Lines are sent newline delimited over a persistent connection, each prefixed with the api key,
+/// as text or as JSON objects with [`crate::config::PayloadFormat::Json`]. Runs the same poll
+/// loop as [`DataDogHttpWriter`], so buffering, flushing, retries, stats and the adapter's flush
+/// signal behave the same. Creating it fails if the configuration sets options only the HTTP
+/// intake supports, such as a proxy, compression or OTLP payloads
+pub struct DataDogTcpWriter {
+    /// Writer whose sends go to the TCP intake
+    writer: DataDogHttpWriter,
+}
+
+impl DataDogTcpWriter {
+    /// Create new [`DataDogTcpWriter`] sending to the TCP intake at `address`
+    pub fn new(
+        datadog_config: DataDogConfig,
+        address: String,
+        flush_interval: Option<Duration>,
+        logs: flume::Receiver<LogEvent>,
+        flush_request: flume::Receiver<()>,
+        flush_response: flume::Sender<Result<usize, Error>>,
+    ) -> Result<Self, Error> {
+        let (_, flush_signal) = flume::bounded(1);
+        Self::from_channels(
+            datadog_config,
+            address,
+            flush_interval,
+            WriterChannels {
+                logs,
+                flush_request,
+                flush_response,
+                flush_signal,
+                stats: Arc::default(),
+            },
+        )
+    }
+
+    /// Create new [`DataDogTcpWriter`] on the writer ends of an adapter's channels
+    pub(crate) fn from_channels(
+        mut datadog_config: DataDogConfig,
+        address: String,
+        flush_interval: Option<Duration>,
+        channels: WriterChannels,
+    ) -> Result<Self, Error> {
+        let unsupported = http_only_options(&datadog_config);
+        if !unsupported.is_empty() {
+            return Err(ConfigError(format!(
+                "The DataDog TCP intake doesn't support {}",
+                unsupported.join(", ")
+            )));
+        }
+        let transport = TcpTransport::new(&datadog_config, address);
+        // Nothing bounds the buffer otherwise while the intake is unreachable
+        datadog_config.max_buffer_bytes = datadog_config
+            .max_buffer_bytes
+            .or(Some(DEFAULT_TCP_BUFFER_BYTES));
+        let writer = DataDogHttpWriter::from_channels(datadog_config, flush_interval, channels)?
+            .with_tcp(transport);
+        Ok(Self { writer })
+    }
+
+    /// Writer poll loop.
+    ///
+    /// This is what drives the actual execution of the logger
+    pub async fn poll(&mut self) {
+        self.writer.poll().await
+    }
+
+    /// Counters describing the writer, shared with the adapter
+    pub fn stats(&self) -> Arc<WriterStats> {
+        self.writer.stats()
+    }
+}
@@ -0,0 +1,62 @@
+//! Local file copy of every log line with simple size based rotation
+
+use crate::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Appends log lines to a local file, rotating it to `<path>.1` when full
+pub(crate) struct LocalTee {
+    /// File being written to
+    path: PathBuf,
+    /// Maximum size of the file before it is rotated
+    max_bytes: usize,
+    /// Open file handle, opened lazily
+    file: Option<File>,
+    /// Bytes written to the current file
+    size: usize,
+}
+
+impl LocalTee {
+    /// Create new [`LocalTee`]
+    pub(crate) fn new(path: PathBuf, max_bytes: usize) -> Self {
+        Self {
+            path,
+            max_bytes,
+            file: None,
+            size: 0,
+        }
+    }
+
+    /// Append a line, rotating first if it would exceed the maximum size
+    pub(crate) fn write(&mut self, line: &str) -> Result<(), Error> {
+        let len = line.len() + 1;
+        if self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                self.size = file.metadata()?.len() as usize;
+                self.file.insert(file)
+            }
+        };
+        writeln!(file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Move the current file to `<path>.1` and start a new one
+    fn rotate(&mut self) -> Result<(), Error> {
+        self.file = None;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        self.size = 0;
+        Ok(())
+    }
+}
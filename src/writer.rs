@@ -1,224 +1,2517 @@
 //! Writer task that posts data to the api
 
-use crate::error::Error::ChannelError;
+use crate::config::{
+    format_tag, ApiKeyProvider, ClientRecycle, Compression as BodyCompression, DroppedLineCallback,
+    ErrorCallback, FlushLatencyCallback, GzipMode, OversizedLinePolicy, PayloadFormat,
+    TimestampPrecision, API_KEY_PLACEHOLDER, MAX_PAYLOAD_BYTES,
+};
+use crate::error::Error::{ApiError, ChannelError, CircuitOpen, ConfigError, FlushError};
 use crate::error::{log_error, Error};
+use crate::event::{target_matches, LogEvent};
+use crate::otlp;
+use crate::rt;
+use crate::stats::WriterStats;
+#[cfg(feature = "tcp")]
+use crate::tcp::TcpTransport;
+use crate::tee::LocalTee;
 use crate::DataDogConfig;
 use chrono::{DateTime, Duration, Utc};
-use flume::RecvTimeoutError;
-use itertools::Itertools;
-use log::debug;
-use reqwest::header::CONTENT_TYPE;
-use reqwest::Client;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+#[cfg(feature = "stream")]
+use futures_core::Stream;
+use itertools::{Either, Itertools};
+use log::{debug, warn};
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, DATE,
+    RETRY_AFTER,
+};
+use reqwest::{Body, Client, Method, Proxy, Request, RequestBuilder, Response, StatusCode, Url};
+use serde_json::{Map, Value};
+#[cfg(feature = "stream")]
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::{self, Future};
+#[cfg(feature = "stream")]
+use std::io;
+use std::io::{BufRead, Write};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::pin::pin;
+#[cfg(feature = "stream")]
+use std::pin::Pin;
+#[cfg(feature = "stream")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+#[cfg(feature = "stream")]
+use std::task::Context;
+use std::task::Poll;
 use std::time;
 
 /// Default channel recv timeout
 const POLL_TIMEOUT_MS: u64 = 100;
+/// Consecutive authentication failures before sending is disabled
+const AUTH_FAILURE_THRESHOLD: usize = 3;
+/// Marker ending truncated log lines
+const TRUNCATION_MARKER: &str = "...[truncated]";
+/// Routed request urls cached before the cache is cleared
+const MAX_ROUTE_URLS: usize = 256;
+
+/// Deep-merge `attributes` into `target`, nested objects are merged key by key and any other
+/// value in `attributes` replaces the one in `target`
+fn merge_attributes(target: &mut JsonFields, attributes: JsonFields) {
+    for (key, value) in attributes {
+        match (target.get_mut(&key), value) {
+            (Some(Value::Object(existing)), Value::Object(nested)) => {
+                merge_attributes(existing, nested)
+            }
+            (_, value) => {
+                target.insert(key, value);
+            }
+        }
+    }
+}
+
+/// First multiple of `interval` since the unix epoch that is after `now`
+fn next_aligned_flush(now: DateTime<Utc>, interval: Duration) -> DateTime<Utc> {
+    let interval_ms = interval.num_milliseconds().max(1);
+    let now_ms = now.timestamp_millis();
+    let next_ms = (now_ms.div_euclid(interval_ms) + 1) * interval_ms;
+    DateTime::from_timestamp_millis(next_ms).unwrap_or(now + interval)
+}
+
+/// `now` moved earlier by a random offset below `jitter`, if any
+fn jittered(now: DateTime<Utc>, jitter: Option<Duration>) -> DateTime<Utc> {
+    let range = jitter.and_then(|j| j.num_nanoseconds()).unwrap_or(0);
+    match range {
+        range if range <= 0 => now,
+        range => now - Duration::nanoseconds(fastrand::i64(0..range)),
+    }
+}
+
+/// Cut `line` to at most `max` bytes on a char boundary, ending it with [`TRUNCATION_MARKER`]
+/// unless `max` is too small to fit it
+fn truncate_line(line: &mut String, max: usize) {
+    if line.len() <= max {
+        return;
+    }
+    let marker = match max.checked_sub(TRUNCATION_MARKER.len()) {
+        Some(_) => TRUNCATION_MARKER,
+        None => "",
+    };
+    let mut end = max - marker.len();
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    line.truncate(end);
+    line.push_str(marker);
+}
+
+/// Encoded request body and its content encoding, [`None`] if it is not compressed
+type EncodedBody = (Vec<u8>, Option<&'static str>);
+
+/// Encodes a serialized body, returning the encoded body and its content encoding
+type Encode = dyn Fn(Vec<u8>) -> Result<EncodedBody, Error> + Send + Sync;
+
+/// Fields shared by every log object in a JSON payload
+type JsonFields = Map<String, Value>;
+
+/// How lines are serialized into request bodies
+#[derive(Clone)]
+enum Payload {
+    /// Newline delimited text, with everything but the lines in the query
+    Text,
+    /// JSON array of log objects
+    Json(JsonLog),
+    /// OTLP/JSON request with this resource
+    Otlp(Value),
+}
+
+/// How each line of a JSON payload becomes a log object
+#[derive(Clone)]
+struct JsonLog {
+    /// Fields shared by every log object
+    fields: JsonFields,
+    /// Precision of each log object's `date`
+    precision: TimestampPrecision,
+    /// Add how long each line was buffered as `buffered_ms`
+    buffer_latency: bool,
+}
+
+impl JsonLog {
+    /// JSON log object of a line, with the fields shared by every line
+    fn log(&self, event: &LogEvent) -> Value {
+        let mut log = self.fields.clone();
+        merge_attributes(&mut log, event.attributes.clone());
+        if let Some(source) = &event.source {
+            log.insert("ddsource".to_string(), source.as_str().into());
+        }
+        if let Some(service) = &event.service {
+            log.insert("service".to_string(), service.as_str().into());
+        }
+        if let Some(tags) = &event.tags {
+            let ddtags = match log.get("ddtags").and_then(Value::as_str) {
+                Some(configured) if !configured.is_empty() => format!("{},{}", configured, tags),
+                _ => tags.clone(),
+            };
+            log.insert("ddtags".to_string(), ddtags.into());
+        }
+        log.insert("message".to_string(), event.line.as_str().into());
+        log.insert(
+            "date".to_string(),
+            self.precision.epoch(event.timestamp).into(),
+        );
+        if self.buffer_latency {
+            // Lines are serialized as they are sent, so this is how long the line was buffered
+            let buffered = (Utc::now() - event.timestamp).num_milliseconds().max(0);
+            log.insert("buffered_ms".to_string(), buffered.into());
+        }
+        Value::Object(log)
+    }
+}
+
+/// Encoded request body
+struct Batch {
+    /// Request body, [`None`] if the line was too large to send
+    body: Option<Vec<u8>>,
+    /// Content encoding of the body, [`None`] if it is not compressed
+    encoding: Option<&'static str>,
+    /// Number of log lines in the body
+    lines: usize,
+    /// Overrides shared by every line in a text body
+    route: RequestRoute,
+}
+
+/// Query parameters overriding the configured ones for every line of a text request
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+struct RequestRoute {
+    /// Source overriding the configured `ddsource`
+    source: Option<String>,
+    /// Service overriding the configured service
+    service: Option<String>,
+    /// Comma separated tags added to the configured tags
+    tags: Option<String>,
+}
+
+impl RequestRoute {
+    /// Overrides of `event`
+    fn of(event: &LogEvent) -> Self {
+        Self {
+            source: event.source.clone(),
+            service: event.service.clone(),
+            tags: event.tags.clone(),
+        }
+    }
+}
+
+/// Request bodies of at most the maximum number of lines encoded on demand, halving any range of
+/// lines whose encoded size exceeds the limit. A single line that cannot fit is dropped with an
+/// error
+struct Batches<'a> {
+    /// Lines being encoded
+    lines: &'a [LogEvent],
+    /// Serialization of the lines
+    payload: &'a Payload,
+    /// Maximum encoded size of a body
+    max_bytes: usize,
+    /// Encoding applied to each serialized body
+    encode: &'a Encode,
+    /// Ranges of lines still to encode, the next one last
+    pending: Vec<Range<usize>>,
+}
+
+impl<'a> Batches<'a> {
+    /// Batches of all of `lines`, starting from runs of `max_lines`
+    fn new(
+        lines: &'a [LogEvent],
+        payload: &'a Payload,
+        max_lines: usize,
+        max_bytes: usize,
+        encode: &'a Encode,
+    ) -> Self {
+        let max_lines = max_lines.max(1);
+        Self {
+            lines,
+            payload,
+            max_bytes,
+            encode,
+            pending: (0..lines.len())
+                .step_by(max_lines)
+                .map(|start| start..start.saturating_add(max_lines).min(lines.len()))
+                .rev()
+                .collect(),
+        }
+    }
+}
+
+impl Iterator for Batches<'_> {
+    type Item = Result<Batch, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let range = self.pending.pop()?;
+            let lines = &self.lines[range.clone()];
+            let serialized = DataDogHttpWriter::serialize(lines, self.payload);
+            let (body, encoding) = match (self.encode)(serialized) {
+                Ok(encoded) => encoded,
+                Err(e) => return Some(Err(e)),
+            };
+            if body.len() <= self.max_bytes {
+                return Some(Ok(Batch {
+                    body: Some(body),
+                    encoding,
+                    lines: lines.len(),
+                    route: RequestRoute::default(),
+                }));
+            } else if lines.len() == 1 {
+                log_error(format!(
+                    "Log line of {} bytes exceeds the DataDog request limit of {} bytes, not sending",
+                    body.len(),
+                    self.max_bytes
+                ));
+                return Some(Ok(Batch {
+                    body: None,
+                    encoding,
+                    lines: 1,
+                    route: RequestRoute::default(),
+                }));
+            }
+            let middle = range.start + lines.len() / 2;
+            self.pending.push(middle..range.end);
+            self.pending.push(range.start..middle);
+        }
+    }
+}
+
+/// Lines serialized into a chunk of a streamed request body at a time
+#[cfg(feature = "stream")]
+const STREAM_CHUNK_LINES: usize = 64;
+
+/// Range of lines streamed as one request body
+#[cfg(feature = "stream")]
+struct StreamedBatch {
+    /// Lines in the body
+    range: Range<usize>,
+    /// Serialized size of the body before compression
+    size: usize,
+    /// Overrides shared by every line in a text body
+    route: RequestRoute,
+    /// Whether the single line in the body is too large to send
+    oversized: bool,
+}
+
+/// Request body serialized and gzipped a chunk of lines at a time while it is uploaded, so the
+/// encoded body is never held in memory as a whole
+#[cfg(feature = "stream")]
+struct BodyStream {
+    /// Lines being sent, shared with the writer
+    lines: Arc<Vec<LogEvent>>,
+    /// Serialization of the lines
+    payload: Payload,
+    /// Lines in the body
+    range: Range<usize>,
+    /// Next line to serialize
+    next: usize,
+    /// Gzip encoder of the body, [`None`] if it is not compressed
+    encoder: Option<GzEncoder<Vec<u8>>>,
+    /// Whether the whole body has been produced
+    done: bool,
+    /// Encoded bytes produced so far
+    bytes: Arc<AtomicUsize>,
+}
+
+#[cfg(feature = "stream")]
+impl BodyStream {
+    /// Stream of the lines in `range`, gzipped at `level` if given
+    fn new(
+        lines: Arc<Vec<LogEvent>>,
+        payload: Payload,
+        range: Range<usize>,
+        level: Option<Compression>,
+        bytes: Arc<AtomicUsize>,
+    ) -> Self {
+        Self {
+            lines,
+            payload,
+            next: range.start,
+            range,
+            encoder: level.map(|level| GzEncoder::new(Vec::new(), level)),
+            done: false,
+            bytes,
+        }
+    }
+
+    /// Serialize and encode the next chunk of lines, ending the body after the last one
+    fn encode_chunk(&mut self) -> io::Result<Vec<u8>> {
+        let (prefix, separator, suffix) = DataDogHttpWriter::framing(&self.payload);
+        let end = (self.next + STREAM_CHUNK_LINES).min(self.range.end);
+        let mut chunk = String::new();
+        if self.next == self.range.start {
+            chunk.push_str(prefix);
+        }
+        for event in &self.lines[self.next..end] {
+            if self.next > self.range.start {
+                chunk.push_str(separator);
+            }
+            chunk.push_str(&DataDogHttpWriter::serialize_line(event, &self.payload));
+            self.next += 1;
+        }
+        self.done = self.next == self.range.end;
+        if self.done {
+            chunk.push_str(suffix);
+        }
+        let encoded = match self.encoder.as_mut() {
+            None => chunk.into_bytes(),
+            Some(encoder) => {
+                encoder.write_all(chunk.as_bytes())?;
+                let mut encoded = std::mem::take(encoder.get_mut());
+                if self.done {
+                    if let Some(encoder) = self.encoder.take() {
+                        encoded.extend(encoder.finish()?);
+                    }
+                }
+                encoded
+            }
+        };
+        self.bytes.fetch_add(encoded.len(), Ordering::Relaxed);
+        Ok(encoded)
+    }
+}
+
+#[cfg(feature = "stream")]
+impl Iterator for BodyStream {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let chunk = self.encode_chunk();
+        if chunk.is_err() {
+            self.done = true;
+        }
+        Some(chunk)
+    }
+}
+
+#[cfg(feature = "stream")]
+impl Stream for BodyStream {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Encoding a chunk never waits, so the next chunk is always ready
+        Poll::Ready(self.get_mut().next())
+    }
+}
+
+/// Url and headers shared by every request, serialized once
+struct RequestTemplate {
+    /// DataDog api url including the encoded query
+    url: Url,
+    /// Urls of routed text requests, encoded once per route
+    routes: HashMap<RequestRoute, Url>,
+    /// Static request headers
+    headers: HeaderMap,
+    /// Header carrying the api key
+    api_key_header: HeaderName,
+    /// Value of the api key header, with [`API_KEY_PLACEHOLDER`] replaced by the api key
+    api_key_template: Option<String>,
+}
+
+impl RequestTemplate {
+    /// Encode query and headers
+    fn new(
+        api_host: &str,
+        api_key: &str,
+        api_key_header: &str,
+        api_key_template: Option<String>,
+        query: &[(String, String)],
+        content_type: &'static str,
+    ) -> Result<Self, Error> {
+        let url = Url::parse_with_params(api_host, query)
+            .map_err(|e| ConfigError(format!("Invalid api host {}: {}", api_host, e)))?;
+        let api_key_header = HeaderName::from_bytes(api_key_header.as_bytes()).map_err(|e| {
+            ConfigError(format!("Invalid api key header {}: {}", api_key_header, e))
+        })?;
+        let mut template = Self {
+            url,
+            routes: HashMap::new(),
+            headers: HeaderMap::new(),
+            api_key_header,
+            api_key_template,
+        };
+        let api_key = template.api_key_value(api_key)?;
+        template
+            .headers
+            .insert(template.api_key_header.clone(), api_key);
+        template
+            .headers
+            .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        Ok(template)
+    }
+
+    /// Value of the api key header for `api_key`
+    fn api_key_value(&self, api_key: &str) -> Result<HeaderValue, Error> {
+        let value = match &self.api_key_template {
+            Some(template) => template.replace(API_KEY_PLACEHOLDER, api_key),
+            None => api_key.to_string(),
+        };
+        HeaderValue::from_str(&value).map_err(|e| ConfigError(format!("Invalid api key: {}", e)))
+    }
+
+    /// Url of a text request, with the configured source, service and tags overridden by `route`
+    fn url(&mut self, route: &RequestRoute) -> &Url {
+        if *route == RequestRoute::default() {
+            return &self.url;
+        }
+        // Routes come from records, so a runaway number of them is not kept forever
+        if self.routes.len() >= MAX_ROUTE_URLS && !self.routes.contains_key(route) {
+            self.routes.clear();
+        }
+        self.routes
+            .entry(route.clone())
+            .or_insert_with(|| Self::route_url(&self.url, route))
+    }
+
+    /// Encode the url of `route`
+    fn route_url(url: &Url, route: &RequestRoute) -> Url {
+        let query = url
+            .query_pairs()
+            .map(|(key, value)| {
+                let value = match (key.as_ref(), route) {
+                    (
+                        "ddsource",
+                        RequestRoute {
+                            source: Some(source),
+                            ..
+                        },
+                    ) => source.clone(),
+                    (
+                        "service",
+                        RequestRoute {
+                            service: Some(service),
+                            ..
+                        },
+                    ) => service.clone(),
+                    (
+                        "ddtags",
+                        RequestRoute {
+                            tags: Some(tags), ..
+                        },
+                    ) if value.is_empty() => tags.clone(),
+                    (
+                        "ddtags",
+                        RequestRoute {
+                            tags: Some(tags), ..
+                        },
+                    ) => {
+                        format!("{},{}", value, tags)
+                    }
+                    _ => value.into_owned(),
+                };
+                (key.into_owned(), value)
+            })
+            .collect_vec();
+        let mut url = url.clone();
+        url.query_pairs_mut().clear().extend_pairs(query);
+        url
+    }
+
+    /// Request headers, with the api key from `provider` if given
+    fn headers(&self, provider: Option<&ApiKeyProvider>) -> Result<HeaderMap, Error> {
+        let mut headers = self.headers.clone();
+        if let Some(provider) = provider {
+            headers.insert(
+                self.api_key_header.clone(),
+                self.api_key_value(&provider())?,
+            );
+        }
+        Ok(headers)
+    }
+}
+
+/// Why the poll loop woke up
+enum Wakeup {
+    /// A log line arrived
+    Log(LogEvent),
+    /// The adapter is waiting for a flush
+    FlushRequest,
+    /// The adapter asked for a flush without waiting for it
+    FlushSignal,
+    /// Nothing arrived before a time based flush was due
+    Timeout,
+    /// The adapter has gone away
+    Disconnected,
+}
+
+/// Writer ends of the channels connecting a writer to its adapter
+pub(crate) struct WriterChannels {
+    /// Log lines from the adapter
+    pub(crate) logs: flume::Receiver<LogEvent>,
+    /// Flush requests the adapter waits on
+    pub(crate) flush_request: flume::Receiver<()>,
+    /// Results of requested flushes, back to the adapter
+    pub(crate) flush_response: flume::Sender<Result<usize, Error>>,
+    /// Flush requests the adapter doesn't wait on
+    pub(crate) flush_signal: flume::Receiver<()>,
+    /// Counters shared with the adapter
+    pub(crate) stats: Arc<WriterStats>,
+}
 
 /// API writer
 pub struct DataDogHttpWriter {
     /// HTTP client
     client: Client,
+    /// Static host to address mappings used instead of DNS
+    resolve: Vec<(String, SocketAddr)>,
+    /// When to replace the HTTP client
+    client_recycle: Option<ClientRecycle>,
+    /// When the current HTTP client was created
+    client_created: DateTime<Utc>,
+    /// Requests made by the current HTTP client
+    client_requests: usize,
     /// DataDog api url
     api_host: String,
-    /// DataDog api key
-    api_key: String,
-    /// Query path
-    query: Vec<(String, String)>,
+    /// Pre-serialized url and headers, [`None`] if the configuration is invalid
+    template: Option<RequestTemplate>,
     /// Maximum log lines in a single request
     max_log_lines: usize,
-    /// Maximum allowed request size
+    /// Bytes of buffered log lines that trigger a flush, after compression if bodies are
+    /// compressed
     max_payload_size: usize,
     /// How often to flush writer (never if [`None`])
     flush_interval: Option<Duration>,
-    /// When logs were last flushed
+    /// When logs were last sent or the flush interval last elapsed
     last_flushed: DateTime<Utc>,
+    /// Interval and next boundary of aligned flushes
+    aligned_flush: Option<(Duration, DateTime<Utc>)>,
     /// Log receiver
-    logs: flume::Receiver<String>,
+    logs: flume::Receiver<LogEvent>,
     /// Flush request receiver
     flush_request: flume::Receiver<()>,
     /// Flush response sender
-    flush_response: flume::Sender<Result<(), Error>>,
+    flush_response: flume::Sender<Result<usize, Error>>,
     /// Log buffer
-    buffer_lines: Vec<String>,
+    buffer_lines: Vec<LogEvent>,
     /// Size of buffer
     buffer_size: usize,
+    /// Gzip compression of request bodies
+    gzip: GzipMode,
+    /// Whether compression is picked from the api's advertised encodings
+    negotiate_compression: bool,
+    /// Compression negotiated with the api, once known
+    negotiated_gzip: Option<GzipMode>,
+    /// Local file copy of every line
+    tee: Option<LocalTee>,
+    /// Whether to stop sending after repeated authentication failures
+    auth_failure_shutdown: bool,
+    /// Consecutive authentication failures
+    auth_failures: usize,
+    /// Sending has been disabled and logs are dropped
+    shipping_disabled: bool,
+    /// Targets whose records are flushed immediately
+    flush_on_target: Vec<String>,
+    /// Sort lines in each batch by when they were logged
+    sort_batches: bool,
+    /// Called with the duration of each flush that sent logs
+    flush_latency_callback: Option<FlushLatencyCallback>,
+    /// Flush requests closer together than this are coalesced
+    min_flush_interval: Option<Duration>,
+    /// When the last requested flush was performed
+    last_requested_flush: Option<DateTime<Utc>>,
+    /// A coalesced flush request is waiting for the minimum interval to pass, and for the
+    /// result of the flush performed then
+    deferred_flush: bool,
+    /// A record has requested an immediate flush
+    flush_pending: bool,
+    /// Clock skew warning threshold
+    clock_skew_warning: Option<Duration>,
+    /// Whether clock skew has already been warned about
+    clock_skew_warned: bool,
+    /// Maximum bytes of log lines held before the oldest are evicted
+    max_buffer_bytes: Option<usize>,
+    /// Counters shared with the adapter
+    stats: Arc<WriterStats>,
+    /// Retries of the final flush when the writer shuts down
+    shutdown_retries: usize,
+    /// Proxy url requests to the api are sent through
+    proxy: Option<String>,
+    /// Open a connection to the api when the writer starts
+    connection_warmup: bool,
+    /// Lines that waited longer than this in the channel are dropped
+    max_channel_dwell: Option<Duration>,
+    /// Dictionary used to zstd compress request bodies
+    #[cfg(feature = "zstd")]
+    zstd_dictionary: Option<Arc<[u8]>>,
+    /// Stream request bodies as they are encoded
+    #[cfg(feature = "stream")]
+    streaming_upload: bool,
+    /// Retries of a request that failed with a retryable error
+    max_retries: usize,
+    /// Delay before the first retry, doubled for each following retry
+    retry_base_delay: Duration,
+    /// Longest a Retry-After header may delay a retry
+    max_retry_after: Duration,
+    /// Delay requested by the last 429 response's Retry-After header
+    retry_after: Option<time::Duration>,
+    /// How lines are serialized into request bodies
+    payload: Payload,
+    /// Longest a request to the api may take
+    request_timeout: time::Duration,
+    /// Username and password sent to the proxy
+    proxy_credentials: Option<(String, String)>,
+    /// Longest the final flush may keep retrying
+    shutdown_timeout: Option<time::Duration>,
+    /// Signalled by the adapter to request a flush without waiting for its result
+    flush_signal: flume::Receiver<()>,
+    /// Maximum size of a log line in bytes
+    max_line_size: Option<usize>,
+    /// Behavior for lines over the maximum line size
+    oversized_line_policy: OversizedLinePolicy,
+    /// Callback receiving lines dropped for exceeding the maximum line size
+    on_dropped: Option<DroppedLineCallback>,
+    /// Gzip compression level
+    compression_level: Compression,
+    /// Request body compression
+    compression: BodyCompression,
+    /// File undelivered lines are appended to when a flush fails
+    fallback: Option<LocalTee>,
+    /// Consecutive failed sends that pause sending, and for how long
+    circuit_breaker: Option<(usize, Duration)>,
+    /// Sends that failed since the last successful one
+    consecutive_failures: usize,
+    /// When the paused circuit allows a probe request, [`None`] while closed
+    circuit_open_until: Option<DateTime<Utc>>,
+    /// Callback receiving the writer's errors
+    on_error: Option<ErrorCallback>,
+    /// Called for the api key of each request, in place of the static key
+    api_key_provider: Option<ApiKeyProvider>,
+    /// Uncompressed buffer size at which compressed bodies are next test-compressed
+    next_size_check: usize,
+    /// TCP intake lines are sent to instead of the HTTP api
+    #[cfg(feature = "tcp")]
+    tcp: Option<TcpTransport>,
 }
 
 impl DataDogHttpWriter {
-    /// Create new [`DataDogHttpWriter`]
+    /// Create new [`DataDogHttpWriter`], failing if the configured HTTP client cannot be built
     pub fn new(
         datadog_config: DataDogConfig,
         flush_interval: Option<Duration>,
-        logs: flume::Receiver<String>,
+        logs: flume::Receiver<LogEvent>,
         flush_request: flume::Receiver<()>,
-        flush_response: flume::Sender<Result<(), Error>>,
+        flush_response: flume::Sender<Result<usize, Error>>,
+    ) -> Result<Self, Error> {
+        // Without an adapter to signal, the flush signal channel is disconnected from the start
+        let (_, flush_signal) = flume::bounded(1);
+        Self::from_channels(
+            datadog_config,
+            flush_interval,
+            WriterChannels {
+                logs,
+                flush_request,
+                flush_response,
+                flush_signal,
+                stats: Arc::default(),
+            },
+        )
+    }
+
+    /// Create new [`DataDogHttpWriter`] on the writer ends of an adapter's channels, failing if
+    /// the configured HTTP client cannot be built
+    pub(crate) fn from_channels(
+        datadog_config: DataDogConfig,
+        flush_interval: Option<Duration>,
+        channels: WriterChannels,
+    ) -> Result<Self, Error> {
+        let client = Self::configured_client(&datadog_config)?;
+        Ok(Self::with_client(
+            datadog_config,
+            client,
+            flush_interval,
+            channels,
+        ))
+    }
+
+    /// Create new [`DataDogHttpWriter`] using `client`
+    fn with_client(
+        datadog_config: DataDogConfig,
+        client: Client,
+        flush_interval: Option<Duration>,
+        channels: WriterChannels,
     ) -> Self {
-        let query = vec![
-            ("host".to_string(), datadog_config.hostname),
-            ("service".to_string(), datadog_config.service),
-            ("ddsource".to_string(), datadog_config.source),
-            (
-                "ddtags".to_string(),
-                datadog_config
-                    .tags
-                    .into_iter()
-                    .map(|(k, v)| format!("{}:{}", k, v))
-                    .join(","),
+        let ddtags = datadog_config
+            .tags
+            .into_iter()
+            .map(|(k, v)| format_tag(&k, &v))
+            .join(",");
+        let (query, payload, content_type) = match datadog_config.payload_format {
+            PayloadFormat::Text => (
+                vec![
+                    ("host".to_string(), datadog_config.hostname),
+                    ("service".to_string(), datadog_config.service),
+                    ("ddtags".to_string(), ddtags),
+                    ("ddsource".to_string(), datadog_config.source.clone()),
+                ],
+                Payload::Text,
+                "text/plain",
+            ),
+            PayloadFormat::Json => (
+                vec![],
+                Payload::Json(JsonLog {
+                    fields: {
+                        let mut fields = datadog_config.attributes;
+                        fields.extend([
+                            ("hostname".to_string(), datadog_config.hostname.into()),
+                            ("service".to_string(), datadog_config.service.into()),
+                            ("ddsource".to_string(), datadog_config.source.clone().into()),
+                            ("ddtags".to_string(), ddtags.into()),
+                        ]);
+                        fields
+                    },
+                    precision: datadog_config.timestamp_precision,
+                    buffer_latency: datadog_config.buffer_latency_attr,
+                }),
+                "application/json",
             ),
-        ];
+            PayloadFormat::OtlpJson => (
+                vec![],
+                Payload::Otlp(otlp::resource(
+                    &datadog_config.hostname,
+                    &datadog_config.service,
+                    &datadog_config.source,
+                    &ddtags,
+                )),
+                "application/json",
+            ),
+        };
+        let template = RequestTemplate::new(
+            &datadog_config.api_host,
+            &datadog_config.api_key,
+            &datadog_config.api_key_header,
+            datadog_config.api_key_template.clone(),
+            &query,
+            content_type,
+        )
+        .map_err(log_error)
+        .ok();
         Self {
-            client: Client::default(),
+            client,
+            resolve: datadog_config.resolve,
+            // A client supplied by the application is never replaced
+            client_recycle: match datadog_config.client {
+                Some(_) => None,
+                None => datadog_config.client_recycle,
+            },
+            client_created: Utc::now(),
+            client_requests: 0,
             api_host: datadog_config.api_host,
-            api_key: datadog_config.api_key,
-            query,
+            template,
             max_log_lines: datadog_config.max_log_lines,
             max_payload_size: datadog_config.max_payload_size,
             flush_interval,
-            last_flushed: Utc::now(),
-            logs,
-            flush_request,
-            flush_response,
+            last_flushed: jittered(Utc::now(), datadog_config.flush_jitter),
+            aligned_flush: datadog_config
+                .aligned_flush
+                .map(|d| (d, next_aligned_flush(Utc::now(), d))),
+            logs: channels.logs,
+            flush_request: channels.flush_request,
+            flush_response: channels.flush_response,
             buffer_lines: vec![],
             buffer_size: 0,
+            gzip: datadog_config.gzip,
+            negotiate_compression: datadog_config.negotiated_compression,
+            negotiated_gzip: None,
+            tee: datadog_config
+                .local_tee
+                .map(|(path, max_bytes)| LocalTee::new(path, max_bytes)),
+            auth_failure_shutdown: datadog_config.auth_failure_shutdown,
+            auth_failures: 0,
+            shipping_disabled: false,
+            flush_on_target: datadog_config.flush_on_target,
+            sort_batches: datadog_config.sort_batches,
+            flush_latency_callback: datadog_config.flush_latency_callback,
+            min_flush_interval: datadog_config.min_flush_interval,
+            last_requested_flush: None,
+            deferred_flush: false,
+            flush_pending: false,
+            clock_skew_warning: datadog_config.clock_skew_warning,
+            clock_skew_warned: false,
+            max_buffer_bytes: datadog_config.max_buffer_bytes,
+            stats: channels.stats,
+            shutdown_retries: datadog_config.shutdown_retries,
+            proxy: datadog_config.proxy,
+            connection_warmup: datadog_config.connection_warmup,
+            max_channel_dwell: datadog_config.max_channel_dwell,
+            #[cfg(feature = "zstd")]
+            zstd_dictionary: datadog_config.zstd_dictionary.map(Arc::from),
+            #[cfg(feature = "stream")]
+            streaming_upload: datadog_config.streaming_upload,
+            max_retries: datadog_config.max_retries,
+            retry_base_delay: datadog_config.retry_base_delay,
+            max_retry_after: datadog_config.max_retry_after,
+            retry_after: None,
+            payload,
+            request_timeout: datadog_config.request_timeout.to_std().unwrap_or_default(),
+            proxy_credentials: datadog_config.proxy_credentials,
+            shutdown_timeout: datadog_config
+                .shutdown_timeout
+                .map(|t| t.to_std().unwrap_or_default()),
+            flush_signal: channels.flush_signal,
+            max_line_size: datadog_config.max_line_size,
+            oversized_line_policy: datadog_config.oversized_line_policy,
+            on_dropped: datadog_config.on_dropped,
+            compression_level: Compression::new(datadog_config.compression_level),
+            compression: datadog_config.compression,
+            fallback: datadog_config
+                .fallback_file
+                .map(|path| LocalTee::new(path, usize::MAX)),
+            circuit_breaker: datadog_config.circuit_breaker,
+            consecutive_failures: 0,
+            circuit_open_until: None,
+            on_error: datadog_config.on_error,
+            api_key_provider: datadog_config.api_key_provider.clone(),
+            next_size_check: datadog_config.max_payload_size,
+            #[cfg(feature = "tcp")]
+            tcp: None,
         }
     }
 
+    /// Send to the TCP intake through `transport` instead of the HTTP api
+    #[cfg(feature = "tcp")]
+    pub(crate) fn with_tcp(mut self, transport: TcpTransport) -> Self {
+        self.tcp = Some(transport);
+        self
+    }
+
+    /// HTTP client supplied in the configuration, or one built from it
+    fn configured_client(datadog_config: &DataDogConfig) -> Result<Client, Error> {
+        match &datadog_config.client {
+            Some(client) => Ok(client.clone()),
+            None => Self::new_client(
+                &datadog_config.resolve,
+                datadog_config.proxy.as_deref(),
+                datadog_config.proxy_credentials.as_ref(),
+                datadog_config.request_timeout.to_std().unwrap_or_default(),
+            ),
+        }
+    }
+
+    /// Construct HTTP client with the configured address overrides, proxy, proxy credentials and
+    /// request timeout
+    fn new_client(
+        resolve: &[(String, SocketAddr)],
+        proxy: Option<&str>,
+        proxy_credentials: Option<&(String, String)>,
+        timeout: time::Duration,
+    ) -> Result<Client, Error> {
+        let builder = resolve.iter().fold(
+            Client::builder().timeout(timeout),
+            |builder, (host, addr)| builder.resolve(host, *addr),
+        );
+        let builder = match proxy {
+            Some(proxy) => {
+                let proxy = Proxy::all(proxy)?;
+                builder.proxy(match proxy_credentials {
+                    Some((username, password)) => proxy.basic_auth(username, password),
+                    None => proxy,
+                })
+            }
+            None => builder,
+        };
+        Ok(builder.build()?)
+    }
+
     /// Writer poll loop.
     ///
     /// This is what drives the actual execution of the logger
     pub async fn poll(&mut self) {
-        let timeout = time::Duration::from_millis(POLL_TIMEOUT_MS);
+        if self.connection_warmup {
+            self.warm_up().await;
+        }
         loop {
             // Check if a flush is necessary
             if let Err(e) = self.time_based_flush().await {
-                log_error(e);
-            }
-
-            // Retrieve and handle any new log messages
-            match self.receive_logs(timeout).await {
-                Ok(true) => (),
-                Ok(false) => break,
-                Err(e) => log_error(e),
+                self.report_error(e);
             }
 
-            // Check for any flush requests
-            match self.receive_flush(timeout).await {
-                Ok(true) => (),
-                Ok(false) => break,
-                Err(e) => log_error(e),
+            // Sleep until there is something to do, rather than polling the channels
+            let timeout = self.next_wakeup().map(|at| {
+                (at - Utc::now())
+                    .to_std()
+                    .ok()
+                    .filter(|d| !d.is_zero())
+                    // Retry flushes that are due but failed after the poll timeout, not in a loop
+                    .unwrap_or(time::Duration::from_millis(POLL_TIMEOUT_MS))
+            });
+            let result = match self.wait(timeout).await {
+                Wakeup::Log(event) => {
+                    if self.check_dwell(&event) {
+                        self.on_message(event).await;
+                        self.check_flush().await.map(|_| ())
+                    } else {
+                        Ok(())
+                    }
+                }
+                Wakeup::FlushRequest => self.on_flush_request().await,
+                // Flush if the adapter asked to without waiting for the result
+                Wakeup::FlushSignal => match self.drain().await {
+                    Ok(_) => self.flush().await.map(|_| ()),
+                    Err(e) => Err(e),
+                },
+                Wakeup::Timeout => Ok(()),
+                Wakeup::Disconnected => break,
+            };
+            if let Err(e) = result {
+                self.report_error(e);
             }
         }
 
         // Loop has been exited here from one or all of the channels closing
         // Drain and handle any remaining messages from the log channel and flush one last time
+        let deadline = self.shutdown_timeout.map(|t| time::Instant::now() + t);
         if let Err(e) = self.drain().await {
-            log_error(e);
+            self.report_error(e);
+        }
+        for attempt in 0..=self.shutdown_retries {
+            let flushed = match deadline {
+                Some(deadline) => self.flush_until(deadline).await,
+                None => self.flush().await,
+            };
+            match flushed {
+                Ok(_) => break,
+                Err(e) => self.report_error(e),
+            }
+            let remaining = deadline.map(|d| d.saturating_duration_since(time::Instant::now()));
+            if remaining.is_some_and(|r| r.is_zero()) {
+                break;
+            }
+            if attempt < self.shutdown_retries {
+                let delay = time::Duration::from_millis(POLL_TIMEOUT_MS);
+                rt::sleep(remaining.map_or(delay, |r| r.min(delay))).await;
+            }
+        }
+        if !self.buffer_lines.is_empty() {
+            self.stats.record_unsent(self.buffer_lines.len());
+            self.report_error(FlushError(format!(
+                "Shutting down without sending {} log lines",
+                self.buffer_lines.len()
+            )));
+        }
+    }
+
+    /// Ship newline delimited lines from `reader` instead of the log channel, returning the
+    /// number of lines delivered. Empty lines are skipped
+    pub async fn replay<R: BufRead>(&mut self, reader: R) -> Result<usize, Error> {
+        let mut delivered = 0;
+        for line in reader.lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            self.on_message(LogEvent::new(line, String::new(), Utc::now()))
+                .await;
+            delivered += self.check_flush().await?;
+        }
+        Ok(delivered + self.flush().await?)
+    }
+
+    /// Counters describing the writer, shared with the adapter
+    pub fn stats(&self) -> Arc<WriterStats> {
+        self.stats.clone()
+    }
+
+    /// Wait until a log line, flush request or flush signal arrives, or until `timeout` if
+    /// given. The task sleeps in the meantime, leaving the thread to the runtime
+    async fn wait(&self, timeout: Option<time::Duration>) -> Wakeup {
+        let mut log = pin!(self.logs.recv_async());
+        let mut flush_request = pin!(self.flush_request.recv_async());
+        let mut flush_signal = pin!(self.flush_signal.recv_async());
+        let wakeup = future::poll_fn(|cx| {
+            if let Poll::Ready(l) = log.as_mut().poll(cx) {
+                return Poll::Ready(l.map_or(Wakeup::Disconnected, Wakeup::Log));
+            }
+            if let Poll::Ready(r) = flush_request.as_mut().poll(cx) {
+                return Poll::Ready(r.map_or(Wakeup::Disconnected, |_| Wakeup::FlushRequest));
+            }
+            // A disconnected signal channel only means nothing can signal any more
+            match flush_signal.as_mut().poll(cx) {
+                Poll::Ready(Ok(())) => Poll::Ready(Wakeup::FlushSignal),
+                _ => Poll::Pending,
+            }
+        });
+        match timeout {
+            Some(timeout) => rt::timeout(timeout, wakeup)
+                .await
+                .unwrap_or(Wakeup::Timeout),
+            None => wakeup.await,
+        }
+    }
+
+    /// When a time based flush is next due, or [`None`] if only messages can trigger one.
+    /// While the circuit breaker is open, that is when its cooldown ends
+    fn next_wakeup(&self) -> Option<DateTime<Utc>> {
+        // A deferred flush is due even while the circuit breaker is open, to answer its requester
+        let deferred = match (self.deferred_flush, self.last_requested_flush) {
+            (true, Some(at)) => self.min_flush_interval.map(|min| at + min),
+            _ => None,
+        };
+        if self.circuit_open() {
+            return deferred.into_iter().chain(self.circuit_open_until).min();
+        }
+        let scheduled = match self.aligned_flush {
+            Some((_, next)) => Some(next),
+            None => self.flush_interval.map(|d| self.last_flushed + d),
+        };
+        deferred.into_iter().chain(scheduled).min()
+    }
+
+    /// Record how long the event spent in the channel, returning whether it is fresh enough to
+    /// send
+    fn check_dwell(&self, event: &LogEvent) -> bool {
+        let dwell = Utc::now() - event.timestamp;
+        self.stats
+            .record_received(dwell.to_std().unwrap_or_default());
+        match self.max_channel_dwell {
+            Some(max) if dwell > max => {
+                self.stats.record_stale();
+                false
+            }
+            _ => true,
         }
-        if let Err(e) = self.flush().await {
-            log_error(e);
+    }
+
+    /// Handle a flush request from the adapter, responding with its result
+    async fn on_flush_request(&mut self) -> Result<(), Error> {
+        if self.flush_coalesced() {
+            // Too soon after the last flush. The requester waits for the single flush performed
+            // once the minimum interval has passed, which sends its lines, and gets its result
+            self.deferred_flush = true;
+            return Ok(());
         }
+        let flush_result = self.requested_flush().await;
+        self.respond(flush_result).await
     }
 
-    /// Receive and process any incoming log lines
-    async fn receive_logs(&mut self, timeout: time::Duration) -> Result<bool, Error> {
-        match self.logs.recv_timeout(timeout) {
-            Ok(l) => {
-                self.on_message(l).await;
-                self.check_flush().await?;
-                Ok(true)
+    /// Perform a flush requested by the adapter, reporting any error
+    async fn requested_flush(&mut self) -> Result<usize, Error> {
+        // Requests are awaited one at a time, so by the time the result is returned every
+        // request from earlier flushes has completed. The result carries the number of lines
+        // sent, including any sent while draining
+        let flush_result = match self.drain().await {
+            Ok(drained) => self.flush().await.map(|sent| drained + sent),
+            Err(e) => Err(e),
+        }
+        .map_err(|e| {
+            eprintln!("Failed to flush logs: {}", e);
+            if let Some(on_error) = self.on_error.as_ref() {
+                on_error(&e);
             }
-            Err(RecvTimeoutError::Timeout) => Ok(true),
-            Err(RecvTimeoutError::Disconnected) => Ok(false),
+            e
+        });
+        self.last_requested_flush = Some(Utc::now());
+        flush_result
+    }
+
+    /// Send a flush result to the adapter. The adapter may have stopped waiting after its flush
+    /// timeout, so the result is discarded if nobody receives it within the poll timeout
+    async fn respond(&self, result: Result<usize, Error>) -> Result<(), Error> {
+        match rt::timeout(
+            time::Duration::from_millis(POLL_TIMEOUT_MS),
+            self.flush_response.send_async(result),
+        )
+        .await
+        {
+            Some(Ok(())) | None => Ok(()),
+            Some(Err(e)) => Err(ChannelError(format!(
+                "Failed to send flush response: {}",
+                e
+            ))),
+        }
+    }
+
+    /// Whether the previous requested flush was within the minimum flush interval, so a new
+    /// request is coalesced with the next flush
+    fn flush_coalesced(&self) -> bool {
+        match (self.min_flush_interval, self.last_requested_flush) {
+            (Some(min_interval), Some(at)) => Utc::now() < at + min_interval,
+            _ => false,
         }
     }
 
-    /// Receive and process any incoming flush requests
-    async fn receive_flush(&mut self, timeout: time::Duration) -> Result<bool, Error> {
-        match self.flush_request.recv_timeout(timeout / 2) {
-            Ok(_) => {
-                // On flush request, perform a flush and send the result back over the channel
-                self.drain().await?;
-                let flush_result = self.flush().await.map_err(|e| {
-                    eprintln!("Failed to flush logs: {}", e);
-                    e
-                });
-                self.flush_response
-                    .send(flush_result)
-                    .map_err(|e| ChannelError(format!("Failed to send flush response: {}", e)))?;
-                Ok(true)
+    /// Handle incoming log event
+    async fn on_message(&mut self, mut event: LogEvent) {
+        #[cfg(feature = "record")]
+        crate::recorder::recorder().record(&event.line);
+        if let Some(tee) = self.tee.as_mut() {
+            if let Err(e) = tee.write(&event.line) {
+                self.report_error(e);
+            }
+        }
+        if self
+            .flush_on_target
+            .iter()
+            .any(|t| target_matches(&event.target, t))
+        {
+            self.flush_pending = true;
+        }
+        if let Some(max) = self.max_line_size.filter(|max| event.line.len() > *max) {
+            match self.oversized_line_policy {
+                OversizedLinePolicy::Truncate => truncate_line(&mut event.line, max),
+                OversizedLinePolicy::Drop => {
+                    warn!(
+                        "Log line of {} bytes exceeds the maximum of {} bytes, not sending",
+                        event.line.len(),
+                        max
+                    );
+                    self.stats.record_oversized(1);
+                    if let Some(callback) = self.on_dropped.as_ref() {
+                        callback(&event.line);
+                    }
+                    return;
+                }
             }
-            Err(RecvTimeoutError::Timeout) => Ok(true),
-            Err(RecvTimeoutError::Disconnected) => Ok(false),
         }
+        self.buffer_size += event.line.len();
+        self.buffer_lines.push(event);
+        self.evict();
+        self.record_buffer();
     }
 
-    /// Handle incoming log line
-    async fn on_message(&mut self, message: String) {
-        self.buffer_size += message.as_bytes().len();
-        self.buffer_lines.push(message);
+    /// Publish the size of the buffer to the shared stats
+    fn record_buffer(&self) {
+        self.stats
+            .record_buffer(self.buffer_lines.len(), self.buffer_size);
     }
 
-    /// Flush log lines in buffer
-    async fn flush(&mut self) -> Result<(), Error> {
-        if self.buffer_size > 0 {
+    /// Evict the oldest lines while the buffer exceeds its maximum size
+    fn evict(&mut self) {
+        let max_bytes = match self.max_buffer_bytes {
+            Some(max_bytes) if self.buffer_size > max_bytes => max_bytes,
+            _ => return,
+        };
+        let mut evicted_bytes = 0;
+        let evicted_lines = self
+            .buffer_lines
+            .iter()
+            .take_while(|e| {
+                let evict = self.buffer_size - evicted_bytes > max_bytes;
+                if evict {
+                    evicted_bytes += e.line.len();
+                }
+                evict
+            })
+            .count();
+        self.buffer_lines.drain(..evicted_lines);
+        self.buffer_size -= evicted_bytes;
+        self.stats.record_eviction(evicted_lines, evicted_bytes);
+        warn!(
+            "DataDog buffer exceeded {} bytes, evicted {} lines",
+            max_bytes, evicted_lines
+        );
+    }
+
+    /// Flush log lines in buffer, returning the number of lines sent
+    async fn flush(&mut self) -> Result<usize, Error> {
+        self.flush_pending = false;
+        let mut sent_lines = 0;
+        if self.shipping_disabled {
+            if !self.buffer_lines.is_empty() {
+                self.stats
+                    .record_dropped(self.buffer_lines.len(), self.buffer_size);
+                // Kept in the fallback file for replaying with a valid api key
+                self.write_fallback();
+            }
+            self.buffer_lines = vec![];
+            self.buffer_size = 0;
+        } else if self.buffer_size > 0 {
             debug!("Flushing logger");
-            self.send().await?;
+            let started = time::Instant::now();
+            let sent = self.send().await;
+            if let Some(callback) = self.flush_latency_callback.as_ref() {
+                callback(started.elapsed());
+            }
+            sent?;
+            sent_lines = self.buffer_lines.len();
             self.buffer_lines = vec![];
             self.buffer_size = 0;
             self.last_flushed = Utc::now();
         }
-        Ok(())
+        self.next_size_check = self.max_payload_size;
+        self.record_buffer();
+        Ok(sent_lines)
+    }
+
+    /// Flush like [`Self::flush`], giving up once `deadline` passes even if a send is still
+    /// retrying. The lines are then kept in the buffer, or moved to the fallback file if there is
+    /// one
+    async fn flush_until(&mut self, deadline: time::Instant) -> Result<usize, Error> {
+        // Sends take the lines out of the buffer, so an interrupted send would lose them
+        let lines = self.buffer_lines.clone();
+        let remaining = deadline.saturating_duration_since(time::Instant::now());
+        match rt::timeout(remaining, self.flush()).await {
+            Some(result) => result,
+            None => {
+                // Which of the interrupted send's requests were delivered is unknown, so all of
+                // its lines are kept
+                let unsent = lines.len();
+                self.buffer_lines = lines;
+                self.write_fallback();
+                self.buffer_size = self.buffer_lines.iter().map(|e| e.line.len()).sum();
+                self.record_buffer();
+                Err(FlushError(format!(
+                    "Timed out flushing {} log lines",
+                    unsent
+                )))
+            }
+        }
     }
 
-    /// Post data to api
+    /// Post buffered lines to api, split into as many requests as needed to fit the request limit.
+    /// Requests are encoded one at a time as they are posted, so only one encoded body is held
+    /// in memory alongside the buffer.
+    ///
+    /// If a request fails, lines from requests that were already delivered are removed from the
+    /// buffer so they aren't sent again
     async fn send(&mut self) -> Result<(), Error> {
+        if self.circuit_open() {
+            return Err(CircuitOpen);
+        }
         debug!("Sending {} log lines", self.buffer_lines.len());
-        match self
+        if self.sort_batches {
+            self.buffer_lines.sort_by_key(|e| e.timestamp);
+        }
+        if matches!(self.payload, Payload::Text) {
+            // Stable, so lines keep their order within each route
+            self.buffer_lines
+                .sort_by(|a, b| a.route_key().cmp(&b.route_key()));
+        }
+        // Batches borrow the lines while posting needs the writer, so both are moved out
+        let payload = self.payload.clone();
+        let lines = std::mem::take(&mut self.buffer_lines);
+        #[cfg(feature = "tcp")]
+        if self.tcp.is_some() {
+            let (sent_lines, result) = self.send_tcp(&lines, &payload).await;
+            self.buffer_lines = lines;
+            return self.sent(sent_lines, result);
+        }
+        #[cfg(feature = "stream")]
+        if self.streams() {
+            let lines = Arc::new(lines);
+            let (sent_lines, result) = self.post_streamed(&lines, &payload).await;
+            // Bodies are dropped once sent, leaving the writer as the only owner
+            self.buffer_lines = Arc::try_unwrap(lines).unwrap_or_else(|lines| lines.to_vec());
+            return self.sent(sent_lines, result);
+        }
+        let encode = self.encoder().await;
+        let (sent_lines, result) = self
+            .post_batches(Self::batches(
+                &lines,
+                &payload,
+                self.max_log_lines,
+                MAX_PAYLOAD_BYTES,
+                &*encode,
+            ))
+            .await;
+        self.buffer_lines = lines;
+        self.sent(sent_lines, result)
+    }
+
+    /// Write lines to the TCP intake, at most [`Self::max_log_lines`] per write, retrying failed
+    /// writes with exponential backoff, returning the number of lines written before any failure
+    #[cfg(feature = "tcp")]
+    async fn send_tcp(
+        &mut self,
+        lines: &[LogEvent],
+        payload: &Payload,
+    ) -> (usize, Result<(), Error>) {
+        let tcp = match self.tcp.as_mut() {
+            Some(tcp) => tcp,
+            None => return (0, Ok(())),
+        };
+        let api_key = tcp.api_key();
+        let mut sent_lines = 0;
+        for chunk in lines.chunks(self.max_log_lines.max(1)) {
+            let body = chunk
+                .iter()
+                .map(|e| match payload {
+                    Payload::Json(json) => format!("{} {}\n", api_key, json.log(e)),
+                    _ => format!("{} {}\n", api_key, e.line),
+                })
+                .join("");
+            let mut attempt = 0;
+            loop {
+                match tcp.send(body.as_bytes()).await {
+                    Ok(()) => break,
+                    // A probe after the circuit breaker's cooldown is a single write
+                    Err(e) if attempt < self.max_retries && self.circuit_open_until.is_none() => {
+                        let delay = Self::backoff(self.retry_base_delay, attempt);
+                        warn!(
+                            "Failed to send logs to the DataDog TCP intake, retrying in {:?}: {}",
+                            delay, e
+                        );
+                        self.stats.record_retry();
+                        rt::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    Err(e) => {
+                        self.stats.record_failed_batch();
+                        return (sent_lines, Err(e));
+                    }
+                }
+            }
+            self.stats.record_sent(chunk.len(), body.len());
+            sent_lines += chunk.len();
+        }
+        (sent_lines, Ok(()))
+    }
+
+    /// Update the circuit breaker after sending, and remove the `sent_lines` delivered before a
+    /// failure from the buffer
+    fn sent(&mut self, sent_lines: usize, result: Result<(), Error>) -> Result<(), Error> {
+        self.update_circuit(result.is_ok());
+        if result.is_err() {
+            self.buffer_lines.drain(..sent_lines);
+            self.write_fallback();
+            self.buffer_size = self.buffer_lines.iter().map(|e| e.line.len()).sum();
+            self.record_buffer();
+        }
+        result
+    }
+
+    /// Log an error and pass it to the error callback, if any
+    fn report_error(&self, e: Error) {
+        if let Some(on_error) = self.on_error.as_ref() {
+            on_error(&e);
+        }
+        log_error(e);
+    }
+
+    /// Whether the circuit breaker is pausing sends, false once its cooldown has elapsed
+    fn circuit_open(&self) -> bool {
+        self.circuit_open_until
+            .is_some_and(|until| Utc::now() < until)
+    }
+
+    /// Track consecutive failed sends, pausing sends once they reach the circuit breaker
+    /// threshold or a probe after the cooldown fails, and resuming after a successful send
+    fn update_circuit(&mut self, succeeded: bool) {
+        let (threshold, cooldown) = match self.circuit_breaker {
+            Some(circuit_breaker) => circuit_breaker,
+            None => return,
+        };
+        if succeeded {
+            self.consecutive_failures = 0;
+            if self.circuit_open_until.take().is_some() {
+                warn!("DataDog reachable again, resuming sends");
+                self.stats.record_circuit_closed();
+            }
+            return;
+        }
+        self.consecutive_failures += 1;
+        if self.circuit_open_until.is_some() || self.consecutive_failures >= threshold {
+            warn!(
+                "{} consecutive failed sends to DataDog, pausing sends for {}s",
+                self.consecutive_failures,
+                cooldown.num_seconds()
+            );
+            self.circuit_open_until = Some(Utc::now() + cooldown);
+            self.stats.record_circuit_open();
+        }
+    }
+
+    /// Move undelivered lines from the buffer to the fallback file, if any, keeping the lines
+    /// that couldn't be written
+    fn write_fallback(&mut self) {
+        let fallback = match self.fallback.as_mut() {
+            Some(fallback) => fallback,
+            None => return,
+        };
+        let mut error = None;
+        let written = self
+            .buffer_lines
+            .iter()
+            .take_while(|event| match fallback.write(&event.line) {
+                Ok(()) => true,
+                Err(e) => {
+                    error = Some(e);
+                    false
+                }
+            })
+            .count();
+        if let Some(e) = error {
+            self.report_error(e);
+        }
+        warn!(
+            "DataDog unreachable, wrote {} log lines to fallback file",
+            written
+        );
+        self.buffer_lines.drain(..written);
+    }
+
+    /// Request body encoding: zstd with the pre-shared dictionary if there is one, otherwise the
+    /// configured [`BodyCompression`], negotiating gzip with the api if enabled
+    async fn encoder(&mut self) -> Box<Encode> {
+        #[cfg(feature = "zstd")]
+        if let Some(dictionary) = self.zstd_dictionary.clone() {
+            return Box::new(move |body| {
+                Self::compress_zstd(&body, &dictionary).map(|body| (body, Some("zstd")))
+            });
+        }
+        #[cfg(feature = "zstd")]
+        if self.compression == BodyCompression::Zstd {
+            return Box::new(|body| Ok((zstd::bulk::compress(&body, 0)?, Some("zstd"))));
+        }
+        let gzip = self.gzip_mode().await;
+        Self::gzip_encoder(gzip, self.compression_level)
+    }
+
+    /// When bodies are gzipped, negotiating with the api if enabled. Never for anything but
+    /// [`BodyCompression::Gzip`]
+    async fn gzip_mode(&mut self) -> GzipMode {
+        match self.compression {
+            BodyCompression::Gzip if self.negotiate_compression => self.negotiate_gzip().await,
+            BodyCompression::Gzip => self.gzip,
+            _ => GzipMode::Never,
+        }
+    }
+
+    /// Lazily encode `lines` into request bodies of at most `max_lines` lines and `max_bytes`
+    /// bytes with `encode`, in order. Text payloads carry their source, service and tags in the
+    /// query, so each run of lines with the same overrides is encoded separately
+    fn batches<'a>(
+        lines: &'a [LogEvent],
+        payload: &'a Payload,
+        max_lines: usize,
+        max_bytes: usize,
+        encode: &'a Encode,
+    ) -> impl Iterator<Item = Result<Batch, Error>> + Send + 'a {
+        let per_route = matches!(payload, Payload::Text);
+        let groups = if per_route {
+            Either::Left(lines.chunk_by(|a, b| a.route_key() == b.route_key()))
+        } else {
+            Either::Right(std::iter::once(lines))
+        };
+        groups.flat_map(move |group| {
+            let route = group
+                .first()
+                .filter(|_| per_route)
+                .map(RequestRoute::of)
+                .unwrap_or_default();
+            Batches::new(group, payload, max_lines, max_bytes, encode).map(move |batch| {
+                batch.map(|batch| Batch {
+                    route: route.clone(),
+                    ..batch
+                })
+            })
+        })
+    }
+
+    /// Whether request bodies are streamed, which needs gzip or no compression and text or JSON
+    /// payloads
+    #[cfg(feature = "stream")]
+    fn streams(&self) -> bool {
+        #[cfg(feature = "zstd")]
+        if self.zstd_dictionary.is_some() || self.compression == BodyCompression::Zstd {
+            return false;
+        }
+        self.streaming_upload && !matches!(self.payload, Payload::Otlp(_))
+    }
+
+    /// Post `lines` as streamed request bodies in order, returning how many lines were consumed
+    /// before a request failure stopped posting
+    #[cfg(feature = "stream")]
+    async fn post_streamed(
+        &mut self,
+        lines: &Arc<Vec<LogEvent>>,
+        payload: &Payload,
+    ) -> (usize, Result<(), Error>) {
+        let gzip = self.gzip_mode().await;
+        let level = self.compression_level;
+        let mut sent_lines = 0;
+        let max_lines = self.max_log_lines;
+        for batch in Self::streamed_batches(lines, payload, max_lines, MAX_PAYLOAD_BYTES) {
+            let count = batch.range.len();
+            if batch.oversized {
+                log_error(format!(
+                    "Log line of {} bytes exceeds the DataDog request limit of {} bytes, not sending",
+                    batch.size, MAX_PAYLOAD_BYTES
+                ));
+                self.stats.record_oversized(count);
+                sent_lines += count;
+                continue;
+            }
+            let gzipped = match gzip {
+                GzipMode::Never => false,
+                GzipMode::Always => true,
+                GzipMode::Auto(min_bytes) => batch.size >= min_bytes,
+            };
+            let bytes = Arc::new(AtomicUsize::new(0));
+            let body = || {
+                bytes.store(0, Ordering::Relaxed);
+                Body::wrap_stream(BodyStream::new(
+                    lines.clone(),
+                    payload.clone(),
+                    batch.range.clone(),
+                    gzipped.then_some(level),
+                    bytes.clone(),
+                ))
+            };
+            let encoding = gzipped.then_some("gzip");
+            if let Err(e) = self.post_with_retries(&body, encoding, &batch.route).await {
+                self.stats.record_failed_batch();
+                return (sent_lines, Err(e));
+            }
+            self.stats.record_sent(count, bytes.load(Ordering::Relaxed));
+            sent_lines += count;
+        }
+        (sent_lines, Ok(()))
+    }
+
+    /// Split `lines` into streamed request bodies of at most `max_lines` lines whose uncompressed
+    /// size fits in `max_bytes`, keeping runs of lines with the same overrides together for text
+    /// payloads. A line that cannot fit on its own gets a body marked oversized
+    #[cfg(feature = "stream")]
+    fn streamed_batches(
+        lines: &[LogEvent],
+        payload: &Payload,
+        max_lines: usize,
+        max_bytes: usize,
+    ) -> Vec<StreamedBatch> {
+        let (prefix, separator, suffix) = Self::framing(payload);
+        let per_route = matches!(payload, Payload::Text);
+        let mut batches: Vec<StreamedBatch> = vec![];
+        for (i, event) in lines.iter().enumerate() {
+            // JSON lines are serialized again while streaming, only their size is kept here
+            let size = Self::serialize_line(event, payload).len();
+            match batches.last_mut() {
+                Some(last)
+                    if !last.oversized
+                        && last.range.len() < max_lines
+                        && (!per_route
+                            || lines[last.range.start].route_key() == event.route_key())
+                        && last.size + separator.len() + size <= max_bytes =>
+                {
+                    last.range.end = i + 1;
+                    last.size += separator.len() + size;
+                }
+                _ => {
+                    let size = prefix.len() + size + suffix.len();
+                    batches.push(StreamedBatch {
+                        range: i..i + 1,
+                        size,
+                        route: if per_route {
+                            RequestRoute::of(event)
+                        } else {
+                            RequestRoute::default()
+                        },
+                        oversized: size > max_bytes,
+                    });
+                }
+            }
+        }
+        batches
+    }
+
+    /// Text before, between and after the serialized lines of a text or JSON body
+    #[cfg(feature = "stream")]
+    fn framing(payload: &Payload) -> (&'static str, &'static str, &'static str) {
+        match payload {
+            Payload::Json(_) => ("[", ",", "]"),
+            Payload::Text | Payload::Otlp(_) => ("", "\n", ""),
+        }
+    }
+
+    /// A line as serialized in a text or JSON body
+    #[cfg(feature = "stream")]
+    fn serialize_line<'a>(event: &'a LogEvent, payload: &Payload) -> Cow<'a, str> {
+        match payload {
+            Payload::Json(json) => Cow::Owned(json.log(event).to_string()),
+            Payload::Text | Payload::Otlp(_) => Cow::Borrowed(&event.line),
+        }
+    }
+
+    /// Post batches in order as they are encoded, returning how many lines were consumed before
+    /// an encoding or request failure stopped posting
+    async fn post_batches(
+        &mut self,
+        batches: impl Iterator<Item = Result<Batch, Error>> + Send,
+    ) -> (usize, Result<(), Error>) {
+        let mut sent_lines = 0;
+        for batch in batches {
+            let batch = match batch {
+                Ok(batch) => batch,
+                Err(e) => return (sent_lines, Err(e)),
+            };
+            match batch.body {
+                Some(body) => {
+                    let bytes = body.len();
+                    if let Err(e) = self
+                        .post_with_retries(
+                            &|| Body::from(body.clone()),
+                            batch.encoding,
+                            &batch.route,
+                        )
+                        .await
+                    {
+                        self.stats.record_failed_batch();
+                        return (sent_lines, Err(e));
+                    }
+                    self.stats.record_sent(batch.lines, bytes);
+                }
+                None => self.stats.record_oversized(batch.lines),
+            }
+            sent_lines += batch.lines;
+        }
+        (sent_lines, Ok(()))
+    }
+
+    /// Gzip bodies according to the [`GzipMode`]. Each body is compressed as a whole, so a
+    /// gzipped body is always exactly one gzip member that strict single member decoders can read
+    fn gzip_encoder(gzip: GzipMode, level: Compression) -> Box<Encode> {
+        Box::new(move |body| {
+            let gzipped = match gzip {
+                GzipMode::Never => false,
+                GzipMode::Always => true,
+                GzipMode::Auto(min_bytes) => body.len() >= min_bytes,
+            };
+            if gzipped {
+                Self::compress(&body, level).map(|body| (body, Some("gzip")))
+            } else {
+                Ok((body, None))
+            }
+        })
+    }
+
+    /// Serialize lines as newline delimited text, a JSON array of log objects, or an OTLP/JSON
+    /// request
+    fn serialize(lines: &[LogEvent], payload: &Payload) -> Vec<u8> {
+        match payload {
+            Payload::Text => lines.iter().map(|e| &e.line).join("\n").into_bytes(),
+            Payload::Otlp(resource) => otlp::serialize(lines, resource).to_string().into_bytes(),
+            Payload::Json(json) => Value::Array(lines.iter().map(|e| json.log(e)).collect())
+                .to_string()
+                .into_bytes(),
+        }
+    }
+
+    /// Post a single request body to api, retrying retryable failures with exponential backoff,
+    /// or after the delay requested by a 429 response's `Retry-After` header. `body` creates the
+    /// body of each attempt
+    async fn post_with_retries(
+        &mut self,
+        body: &(dyn Fn() -> Body + Send + Sync),
+        encoding: Option<&str>,
+        route: &RequestRoute,
+    ) -> Result<(), Error> {
+        let mut attempt = 0;
+        self.retry_after = None;
+        loop {
+            match self.post(body(), encoding, route).await {
+                // A probe after the circuit breaker's cooldown is a single request
+                Err(e)
+                    if attempt < self.max_retries
+                        && self.circuit_open_until.is_none()
+                        && Self::is_retryable(&e) =>
+                {
+                    let delay = self
+                        .retry_after
+                        .take()
+                        .unwrap_or_else(|| Self::backoff(self.retry_base_delay, attempt));
+                    warn!(
+                        "Failed to send logs to DataDog, retrying in {:?}: {}",
+                        delay, e
+                    );
+                    self.stats.record_retry();
+                    rt::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Whether a failed request may succeed if retried: transport errors, 429 and 5xx
+    fn is_retryable(error: &Error) -> bool {
+        match error {
+            Error::HttpError(e) => e.status().is_none_or(|status| {
+                status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            }),
+            ApiError { status, .. } => {
+                *status == StatusCode::TOO_MANY_REQUESTS.as_u16()
+                    || StatusCode::from_u16(*status).is_ok_and(|s| s.is_server_error())
+            }
+            _ => false,
+        }
+    }
+
+    /// Delay before retry `attempt` (from 0), `base` doubled per attempt plus up to 50% jitter
+    fn backoff(base: Duration, attempt: usize) -> time::Duration {
+        let delay = base.to_std().unwrap_or_default() * 2u32.saturating_pow(attempt as u32);
+        let jitter_range = u64::try_from(delay.as_nanos() / 2).unwrap_or(u64::MAX);
+        delay + time::Duration::from_nanos(fastrand::u64(0..=jitter_range))
+    }
+
+    /// Build the request that would post `body` with the configured source, service and tags,
+    /// without sending it
+    pub fn build_request(&mut self, body: Vec<u8>) -> Result<Request, Error> {
+        Ok(self
+            .request(body.into(), None, &RequestRoute::default())?
+            .build()?)
+    }
+
+    /// Request posting a single request body to api
+    fn request(
+        &mut self,
+        body: Body,
+        encoding: Option<&str>,
+        route: &RequestRoute,
+    ) -> Result<RequestBuilder, Error> {
+        let template = self.template.as_mut().ok_or_else(|| {
+            ConfigError(format!("Cannot send to invalid api host {}", self.api_host))
+        })?;
+        // JSON payloads carry the source, service and tags in the body
+        let url = match self.payload {
+            Payload::Text => template.url(route).clone(),
+            Payload::Json(_) | Payload::Otlp(_) => template.url.clone(),
+        };
+        let request = self
             .client
-            .post(&self.api_host)
-            .query(&self.query)
-            .header("DD-API-KEY", &self.api_key)
-            .header(CONTENT_TYPE, "text/plain")
-            .body(self.buffer_lines.join("\n"))
-            .send()
-            .await
-        {
+            .post(url)
+            .headers(template.headers(self.api_key_provider.as_ref())?);
+        Ok(match encoding {
+            Some(encoding) => request.header(CONTENT_ENCODING, encoding).body(body),
+            None => request.body(body),
+        })
+    }
+
+    /// Post a single request body to api
+    async fn post(
+        &mut self,
+        body: Body,
+        encoding: Option<&str>,
+        route: &RequestRoute,
+    ) -> Result<(), Error> {
+        self.recycle_client();
+        let request = self.request(body, encoding, route)?;
+        self.client_requests += 1;
+        match request.send().await {
             Ok(r) => {
-                r.error_for_status()?;
+                self.check_clock_skew(&r);
+                self.check_auth_failure(r.status());
+                self.check_retry_after(&r);
+                Self::check_status(r).await?;
                 Ok(())
             }
             Err(e) => Err(e.into()),
         }
     }
 
+    /// Turn an error status into [`ApiError`], keeping the body DataDog explains the failure in
+    async fn check_status(response: Response) -> Result<Response, Error> {
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            return Err(ApiError {
+                status: status.as_u16(),
+                body: response.text().await.unwrap_or_default(),
+            });
+        }
+        Ok(response)
+    }
+
+    /// Track consecutive authentication failures and disable sending past the threshold
+    fn check_auth_failure(&mut self, status: StatusCode) {
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            self.auth_failures += 1;
+            if self.auth_failure_shutdown && self.auth_failures >= AUTH_FAILURE_THRESHOLD {
+                self.shipping_disabled = true;
+                self.report_error(FlushError(format!(
+                    "DataDog rejected {} consecutive requests with {}, the api key is likely invalid. No further logs will be sent",
+                    self.auth_failures, status
+                )));
+            }
+        } else {
+            self.auth_failures = 0;
+        }
+    }
+
+    /// Replace the HTTP client if it has reached its configured age or request count
+    fn recycle_client(&mut self) {
+        let expired = match self.client_recycle {
+            None => false,
+            Some(ClientRecycle::Interval(d)) => Utc::now() > self.client_created + d,
+            Some(ClientRecycle::Requests(count)) => self.client_requests >= count,
+        };
+        if expired {
+            debug!("Recycling http client");
+            match Self::new_client(
+                &self.resolve,
+                self.proxy.as_deref(),
+                self.proxy_credentials.as_ref(),
+                self.request_timeout,
+            ) {
+                Ok(client) => self.client = client,
+                Err(e) => self.report_error(e),
+            }
+            self.client_created = Utc::now();
+            self.client_requests = 0;
+        }
+    }
+
+    /// Check the api can be reached and accepts the api key with an authenticated `HEAD`
+    /// request. Any other response status counts as reachable
+    pub async fn ping(&self) -> Result<(), Error> {
+        let template = self.template.as_ref().ok_or_else(|| {
+            ConfigError(format!("Cannot send to invalid api host {}", self.api_host))
+        })?;
+        let response = self
+            .client
+            .head(template.url.clone())
+            .headers(template.headers(self.api_key_provider.as_ref())?)
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Self::check_status(response).await?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Establish a pooled connection to the api with a `HEAD` request, or connect to the TCP
+    /// intake. The response status is irrelevant, only the connection is kept
+    async fn warm_up(&mut self) {
+        #[cfg(feature = "tcp")]
+        if let Some(tcp) = self.tcp.as_mut() {
+            match tcp.connect().await {
+                Ok(_) => debug!("Connected to the DataDog TCP intake"),
+                Err(e) => warn!("Failed to connect to the DataDog TCP intake: {}", e),
+            }
+            return;
+        }
+        if let Some(template) = self.template.as_ref() {
+            match self.client.head(template.url.clone()).send().await {
+                Ok(_) => debug!("Warmed up connection to {}", self.api_host),
+                Err(e) => warn!("Failed to warm up connection to DataDog: {}", e),
+            }
+        }
+    }
+
+    /// Ask the api which encodings it accepts, caching the result
+    async fn negotiate_gzip(&mut self) -> GzipMode {
+        if let Some(gzip) = self.negotiated_gzip {
+            return gzip;
+        }
+        let url = match self.template.as_ref() {
+            Some(template) => template.url.clone(),
+            None => return self.gzip,
+        };
+        let response = match self.client.request(Method::OPTIONS, url).send().await {
+            Ok(r) => Self::check_status(r).await,
+            Err(e) => Err(e.into()),
+        };
+        match response {
+            Ok(r) => {
+                let accepts_gzip = r
+                    .headers()
+                    .get_all(ACCEPT_ENCODING)
+                    .iter()
+                    .filter_map(|v| v.to_str().ok())
+                    .flat_map(|v| v.split(','))
+                    .any(|v| v.trim().eq_ignore_ascii_case("gzip"));
+                let gzip = if accepts_gzip {
+                    GzipMode::Always
+                } else {
+                    GzipMode::Never
+                };
+                debug!("Negotiated compression {:?}", gzip);
+                self.negotiated_gzip = Some(gzip);
+                gzip
+            }
+            Err(e) => {
+                self.report_error(e);
+                self.gzip
+            }
+        }
+    }
+
+    /// Gzip the whole request body as a single member
+    fn compress(body: &[u8], level: Compression) -> Result<Vec<u8>, Error> {
+        let mut encoder = GzEncoder::new(Vec::new(), level);
+        encoder.write_all(body)?;
+        Ok(encoder.finish()?)
+    }
+
+    /// Compress the whole request body with zstd using a pre-shared dictionary
+    #[cfg(feature = "zstd")]
+    fn compress_zstd(body: &[u8], dictionary: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(zstd::bulk::Compressor::with_dictionary(0, dictionary)?.compress(body)?)
+    }
+
+    /// Warn once if the api `Date` header differs from the local clock by more than the threshold
+    fn check_clock_skew(&mut self, response: &Response) {
+        if self.clock_skew_warned {
+            return;
+        }
+        if let Some(threshold) = self.clock_skew_warning {
+            if let Some(date) = response
+                .headers()
+                .get(DATE)
+                .and_then(|d| d.to_str().ok())
+                .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+            {
+                let skew = Utc::now().signed_duration_since(date);
+                if skew.num_milliseconds().abs() > threshold.num_milliseconds() {
+                    warn!(
+                        "Local clock differs from DataDog by {}ms, log timestamps may be inaccurate",
+                        skew.num_milliseconds()
+                    );
+                    self.clock_skew_warned = true;
+                }
+            }
+        }
+    }
+
+    /// Remember the delay requested by a 429 response's `Retry-After` header, given in seconds or
+    /// as an HTTP date, capped at the configured maximum
+    fn check_retry_after(&mut self, response: &Response) {
+        if response.status() != StatusCode::TOO_MANY_REQUESTS {
+            self.retry_after = None;
+            return;
+        }
+        self.retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| match v.trim().parse::<u64>() {
+                Ok(seconds) => Some(time::Duration::from_secs(seconds)),
+                Err(_) => DateTime::parse_from_rfc2822(v)
+                    .ok()
+                    .map(|date| date.signed_duration_since(Utc::now()))
+                    .map(|d| d.to_std().unwrap_or_default()),
+            })
+            .map(|d| d.min(self.max_retry_after.to_std().unwrap_or_default()));
+    }
+
     /// Check if flush interval has elapsed since last send, and flush if so
     async fn time_based_flush(&mut self) -> Result<(), Error> {
-        if let Some(d) = self.flush_interval {
-            if Utc::now() > self.last_flushed + d {
+        if self.deferred_flush && !self.flush_coalesced() {
+            self.deferred_flush = false;
+            let flush_result = self.requested_flush().await;
+            self.respond(flush_result).await?;
+        }
+        if self.circuit_open() {
+            return Ok(());
+        }
+        if let Some((d, next)) = self.aligned_flush {
+            let now = Utc::now();
+            if now >= next {
+                self.aligned_flush = Some((d, next_aligned_flush(now, d)));
+                self.flush().await?;
+            }
+        } else if let Some(d) = self.flush_interval {
+            if Utc::now() >= self.last_flushed + d {
                 self.flush().await?;
+                // Restart the interval even if nothing was sent, so the next flush isn't early
+                self.last_flushed = Utc::now();
             }
         }
         Ok(())
     }
 
-    /// Drain and handle any messages on the log channel
-    async fn drain(&mut self) -> Result<(), Error> {
+    /// Drain and handle any messages on the log channel, returning the number of lines sent
+    async fn drain(&mut self) -> Result<usize, Error> {
         let drained = self.logs.drain().collect_vec();
+        let mut sent_lines = 0;
         for message in drained {
-            self.on_message(message).await;
-            self.check_flush().await?;
+            if self.check_dwell(&message) {
+                self.on_message(message).await;
+                sent_lines += self.check_flush().await?;
+            }
         }
-        Ok(())
+        Ok(sent_lines)
     }
 
-    /// Check if buffer
-    async fn check_flush(&mut self) -> Result<(), Error> {
-        if self.buffer_lines.len() == self.max_log_lines {
-            self.flush().await
-        } else if self.buffer_size >= self.max_payload_size {
+    /// Check if buffer is full or a flush was requested by a record, and flush if so.
+    /// Returns the number of lines sent
+    async fn check_flush(&mut self) -> Result<usize, Error> {
+        if !self.circuit_open()
+            && (self.flush_pending
+                || self.buffer_lines.len() >= self.max_log_lines
+                || self.payload_full().await)
+        {
             self.flush().await
         } else {
-            Ok(())
+            Ok(0)
+        }
+    }
+
+    /// Whether the buffer has reached the maximum payload size. Bodies that would be compressed
+    /// are test-compressed once the uncompressed lines reach it, and are full only once the
+    /// compressed body does
+    async fn payload_full(&mut self) -> bool {
+        if self.buffer_size < self.next_size_check {
+            return false;
+        }
+        let encode = self.encoder().await;
+        let size = match encode(Self::serialize(&self.buffer_lines, &self.payload)) {
+            Ok((body, Some(_))) => body.len(),
+            // Uncompressed bodies are full at the uncompressed size
+            _ => return true,
+        };
+        if size >= self.max_payload_size {
+            return true;
+        }
+        // Check again once the buffer is expected to reach the limit at the current ratio
+        self.next_size_check = (self.buffer_size.saturating_mul(self.max_payload_size)
+            / size.max(1))
+        .max(self.buffer_size + 1);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{GzipMode, TimestampPrecision, MAX_PAYLOAD_BYTES};
+    use crate::event::LogEvent;
+    use crate::writer::{
+        jittered, merge_attributes, next_aligned_flush, truncate_line, Batch, DataDogHttpWriter,
+        JsonLog, Payload, RequestRoute, RequestTemplate, TRUNCATION_MARKER,
+    };
+    use anyhow::Result;
+    use chrono::{DateTime, Duration, Utc};
+    use flate2::bufread::GzDecoder;
+    use flate2::Compression;
+    use itertools::Itertools;
+    use serde_json::{json, Map, Value};
+    use std::io::Read;
+    use std::time;
+
+    fn events(lines: Vec<String>) -> Vec<LogEvent> {
+        lines
+            .into_iter()
+            .map(|l| LogEvent::new(l, "test".to_string(), Utc::now()))
+            .collect_vec()
+    }
+
+    fn batch_requests(
+        lines: &[LogEvent],
+        payload: &Payload,
+        gzip: GzipMode,
+        level: Compression,
+        max_bytes: usize,
+    ) -> Result<Vec<Batch>> {
+        let encode = DataDogHttpWriter::gzip_encoder(gzip, level);
+        Ok(
+            DataDogHttpWriter::batches(lines, payload, usize::MAX, max_bytes, &*encode)
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    }
+
+    #[test]
+    fn test_batch_requests_limit() -> Result<()> {
+        let exact = events(vec![
+            "a".repeat(MAX_PAYLOAD_BYTES / 2),
+            "b".repeat(MAX_PAYLOAD_BYTES / 2 - 1),
+        ]);
+        let batches = batch_requests(
+            &exact,
+            &Payload::Text,
+            GzipMode::Never,
+            Compression::default(),
+            MAX_PAYLOAD_BYTES,
+        )?;
+        assert_eq!(batches.iter().map(|b| b.lines).collect_vec(), vec![2]);
+
+        let over = events(vec![
+            "a".repeat(MAX_PAYLOAD_BYTES / 2),
+            "b".repeat(MAX_PAYLOAD_BYTES / 2),
+        ]);
+        let batches = batch_requests(
+            &over,
+            &Payload::Text,
+            GzipMode::Never,
+            Compression::default(),
+            MAX_PAYLOAD_BYTES,
+        )?;
+        assert_eq!(batches.iter().map(|b| b.lines).collect_vec(), vec![1, 1]);
+        assert!(batches
+            .iter()
+            .all(|b| b.body.as_ref().map(|b| b.len()) == Some(MAX_PAYLOAD_BYTES / 2)));
+
+        let too_large = events(vec!["a".repeat(MAX_PAYLOAD_BYTES + 1), "b".to_string()]);
+        let batches = batch_requests(
+            &too_large,
+            &Payload::Text,
+            GzipMode::Never,
+            Compression::default(),
+            MAX_PAYLOAD_BYTES,
+        )?;
+        assert_eq!(batches.iter().map(|b| b.lines).collect_vec(), vec![1, 1]);
+        assert!(batches[0].body.is_none());
+        assert_eq!(batches[1].body.as_deref(), Some("b".as_bytes()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_requests_split_order() -> Result<()> {
+        // Three lines exceed the limit and are split again, after the first two were sent
+        let lines = events(
+            ["a", "b", "c", "d", "e"]
+                .iter()
+                .map(|l| l.repeat(MAX_PAYLOAD_BYTES / 3 + 1))
+                .collect(),
+        );
+        let batches = batch_requests(
+            &lines,
+            &Payload::Text,
+            GzipMode::Never,
+            Compression::default(),
+            MAX_PAYLOAD_BYTES,
+        )?;
+        assert_eq!(batches.iter().map(|b| b.lines).collect_vec(), vec![2, 1, 2]);
+        let bodies = batches
+            .iter()
+            .map(|b| String::from_utf8_lossy(b.body.as_deref().unwrap_or_default()).into_owned())
+            .collect_vec();
+        let expected = [0..2, 2..3, 3..5]
+            .into_iter()
+            .map(|range| lines[range].iter().map(|e| &e.line).join("\n"))
+            .collect_vec();
+        assert_eq!(bodies, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_requests_max_lines() -> Result<()> {
+        let lines = events((0..25).map(|i| format!("line {}", i)).collect());
+        let encode = DataDogHttpWriter::gzip_encoder(GzipMode::Never, Compression::default());
+        let batches = DataDogHttpWriter::batches(&lines, &Payload::Text, 10, 1000, &*encode)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            batches.iter().map(|b| b.lines).collect_vec(),
+            vec![10, 10, 5]
+        );
+
+        // Whichever of the line count and byte size is reached first splits the lines
+        let batches = DataDogHttpWriter::batches(&lines, &Payload::Text, 10, 50, &*encode)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert!(batches
+            .iter()
+            .all(|b| b.lines <= 10 && b.body.as_ref().is_some_and(|b| b.len() <= 50)));
+        assert_eq!(batches.iter().map(|b| b.lines).sum::<usize>(), lines.len());
+        let bodies = batches
+            .iter()
+            .map(|b| String::from_utf8_lossy(b.body.as_deref().unwrap_or_default()).into_owned())
+            .join("\n");
+        assert_eq!(bodies, lines.iter().map(|e| &e.line).join("\n"));
+        Ok(())
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn test_streamed_body_matches_buffered() -> Result<()> {
+        use crate::writer::BodyStream;
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::Arc;
+
+        let lines = Arc::new(events((0..150).map(|i| format!("line {}", i)).collect()));
+        let fields = serde_json::Map::from_iter([("service".to_string(), json!("test"))]);
+        let json = Payload::Json(JsonLog {
+            fields,
+            precision: TimestampPrecision::Millis,
+            buffer_latency: false,
+        });
+        for payload in [Payload::Text, json] {
+            let buffered = DataDogHttpWriter::serialize(&lines, &payload);
+            for level in [None, Some(Compression::default())] {
+                let bytes = Arc::new(AtomicUsize::new(0));
+                let stream = BodyStream::new(
+                    lines.clone(),
+                    payload.clone(),
+                    0..lines.len(),
+                    level,
+                    bytes.clone(),
+                );
+                let streamed = stream.collect::<Result<Vec<_>, _>>()?.concat();
+                assert_eq!(
+                    bytes.load(std::sync::atomic::Ordering::Relaxed),
+                    streamed.len()
+                );
+                match level {
+                    None => assert_eq!(streamed, buffered),
+                    Some(level) => {
+                        assert_eq!(streamed, DataDogHttpWriter::compress(&buffered, level)?)
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn test_streamed_batches() {
+        let mut lines = events(vec![
+            "aaaa".to_string(),
+            "bbbb".to_string(),
+            "cc".to_string(),
+        ]);
+        lines.push(LogEvent::new(
+            "d".repeat(12),
+            "test".to_string(),
+            Utc::now(),
+        ));
+        let batches = DataDogHttpWriter::streamed_batches(&lines, &Payload::Text, 1000, 10);
+        assert_eq!(
+            batches
+                .iter()
+                .map(|b| (b.range.clone(), b.size, b.oversized))
+                .collect_vec(),
+            vec![(0..2, 9, false), (2..3, 2, false), (3..4, 12, true)]
+        );
+
+        // Split at the line limit before the size limit is reached
+        let batches = DataDogHttpWriter::streamed_batches(&lines[..3], &Payload::Text, 2, 1000);
+        assert_eq!(
+            batches.iter().map(|b| b.range.clone()).collect_vec(),
+            vec![0..2, 2..3]
+        );
+    }
+
+    #[test]
+    fn test_batch_requests_single_gzip_member() -> Result<()> {
+        let lines = events(vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+        let batches = batch_requests(
+            &lines,
+            &Payload::Text,
+            GzipMode::Always,
+            Compression::default(),
+            MAX_PAYLOAD_BYTES,
+        )?;
+        assert_eq!(batches.len(), 1);
+        let body = batches[0].body.as_deref().unwrap_or_default();
+        let mut decoder = GzDecoder::new(body);
+        let mut decoded = String::new();
+        decoder.read_to_string(&mut decoded)?;
+        assert_eq!(decoded, "a\nb\nc");
+        // Nothing may follow the first member
+        assert!(decoder.into_inner().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_requests_compression_level() -> Result<()> {
+        let lines = events((0..1000).map(|i| format!("line {}", i)).collect());
+        let size = |level| -> Result<usize> {
+            let batches = batch_requests(
+                &lines,
+                &Payload::Text,
+                GzipMode::Always,
+                level,
+                MAX_PAYLOAD_BYTES,
+            )?;
+            Ok(batches[0].body.as_ref().map_or(0, |b| b.len()))
+        };
+        assert!(size(Compression::best())? < size(Compression::none())?);
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_dictionary_round_trip() -> Result<()> {
+        let dictionary = b"INFO [my_service::handler] request completed status=200".to_vec();
+        let lines = events(vec![
+            "INFO [my_service::handler] request completed status=200".to_string(),
+            "INFO [my_service::handler] request completed status=404".to_string(),
+        ]);
+        let shared = dictionary.clone();
+        let encode = move |body: Vec<u8>| {
+            DataDogHttpWriter::compress_zstd(&body, &shared).map(|body| (body, Some("zstd")))
+        };
+        let batches =
+            DataDogHttpWriter::batches(&lines, &Payload::Text, 1000, MAX_PAYLOAD_BYTES, &encode)
+                .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].encoding, Some("zstd"));
+        let body = batches[0].body.as_deref().unwrap_or_default();
+        let decoded = zstd::bulk::Decompressor::with_dictionary(&dictionary)?
+            .decompress(body, MAX_PAYLOAD_BYTES)?;
+        assert_eq!(
+            String::from_utf8(decoded)?,
+            lines.iter().map(|e| &e.line).join("\n")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_jittered() {
+        let now = Utc::now();
+        assert_eq!(jittered(now, None), now);
+        assert_eq!(jittered(now, Some(Duration::zero())), now);
+        let jitter = Duration::milliseconds(500);
+        for _ in 0..100 {
+            let at = jittered(now, Some(jitter));
+            assert!(at <= now && at > now - jitter);
         }
+        // Offsets cover the whole range, not just the sub-second part
+        let jitter = Duration::seconds(5);
+        let offsets = (0..100)
+            .map(|_| now - jittered(now, Some(jitter)))
+            .collect::<Vec<_>>();
+        assert!(offsets
+            .iter()
+            .all(|o| *o >= Duration::zero() && *o < jitter));
+        assert!(offsets.iter().any(|o| *o > Duration::seconds(1)));
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(
+            DataDogHttpWriter::backoff(Duration::zero(), 3),
+            time::Duration::ZERO
+        );
+        // Jitter spans the whole 50%, not just the sub-second part
+        let base = Duration::seconds(2);
+        let delays = (0..100)
+            .map(|_| DataDogHttpWriter::backoff(base, 1))
+            .collect::<Vec<_>>();
+        assert!(delays
+            .iter()
+            .all(|d| { *d >= time::Duration::from_secs(4) && *d <= time::Duration::from_secs(6) }));
+        assert!(delays.iter().any(|d| *d > time::Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_timestamp_precision() {
+        let timestamp = DateTime::parse_from_rfc3339("2024-05-01T12:30:45.123456789Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let event = LogEvent::new("line".to_string(), "test".to_string(), timestamp);
+        for (precision, date, formatted) in [
+            (
+                TimestampPrecision::Seconds,
+                1_714_566_645_i64,
+                "2024-05-01T12:30:45Z",
+            ),
+            (
+                TimestampPrecision::Millis,
+                1_714_566_645_123,
+                "2024-05-01T12:30:45.123Z",
+            ),
+            (
+                TimestampPrecision::Nanos,
+                1_714_566_645_123_456_789,
+                "2024-05-01T12:30:45.123456789Z",
+            ),
+        ] {
+            let json = JsonLog {
+                fields: Map::new(),
+                precision,
+                buffer_latency: false,
+            };
+            let log = json.log(&event);
+            assert_eq!(log["date"], json!(date));
+            assert_eq!(
+                timestamp.to_rfc3339_opts(precision.seconds_format(), true),
+                formatted
+            );
+        }
+        assert_eq!(TimestampPrecision::default(), TimestampPrecision::Millis);
+    }
+
+    #[test]
+    fn test_next_aligned_flush() {
+        let at = |ms| DateTime::from_timestamp_millis(ms).unwrap();
+        let interval = Duration::seconds(10);
+        assert_eq!(next_aligned_flush(at(1_000), interval), at(10_000));
+        assert_eq!(next_aligned_flush(at(9_999), interval), at(10_000));
+        assert_eq!(next_aligned_flush(at(10_000), interval), at(20_000));
+        assert_eq!(
+            next_aligned_flush(at(1_700_000_003_250), interval),
+            at(1_700_000_010_000)
+        );
+    }
+
+    #[test]
+    fn test_merge_attributes() {
+        let mut target = json!({"env": "prod", "build": {"version": "1.2.3", "arch": "x86"}})
+            .as_object()
+            .cloned()
+            .unwrap();
+        let record = json!({"env": "canary", "build": {"version": "1.2.4"}, "user": 7});
+        merge_attributes(&mut target, record.as_object().cloned().unwrap());
+        assert_eq!(
+            Value::Object(target),
+            json!({"env": "canary", "build": {"version": "1.2.4", "arch": "x86"}, "user": 7})
+        );
+    }
+
+    #[test]
+    fn test_route_url() -> Result<()> {
+        let mut template = RequestTemplate::new(
+            "http://localhost/api",
+            "dummy_key",
+            "DD-API-KEY",
+            None,
+            &[
+                ("service".to_string(), "test".to_string()),
+                ("ddtags".to_string(), "env:test".to_string()),
+                ("ddsource".to_string(), "rust".to_string()),
+            ],
+            "text/plain",
+        )?;
+        let route = RequestRoute {
+            source: Some("nginx".to_string()),
+            service: None,
+            tags: Some("team:web".to_string()),
+        };
+        assert_eq!(
+            template.url(&RequestRoute::default()).query(),
+            Some("service=test&ddtags=env%3Atest&ddsource=rust")
+        );
+        assert_eq!(
+            template.url(&route).query(),
+            Some("service=test&ddtags=env%3Atest%2Cteam%3Aweb&ddsource=nginx")
+        );
+        template.url(&route);
+        assert_eq!(template.routes.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncate_line() {
+        let mut line = "abcdefghijklmnopqrstuvwxyz".to_string();
+        truncate_line(&mut line, 20);
+        assert_eq!(line, "abcdef...[truncated]");
+
+        // Lines within the limit are untouched
+        let mut line = "abcdef".to_string();
+        truncate_line(&mut line, 6);
+        assert_eq!(line, "abcdef");
+
+        // Never splits a multi-byte char, keeping only whole chars before the boundary
+        for (max, expected) in [
+            (20, "ab\u{e9}\u{e9}"),
+            (19, "ab\u{e9}"),
+            (18, "ab\u{e9}"),
+            (17, "ab"),
+        ] {
+            let mut line = format!("ab{}", "\u{e9}".repeat(12));
+            truncate_line(&mut line, max);
+            assert_eq!(line, format!("{}{}", expected, TRUNCATION_MARKER));
+            assert!(line.len() <= max);
+        }
+        for max in 18..=21 {
+            let mut line = "\u{1f600}".repeat(8);
+            truncate_line(&mut line, max);
+            assert_eq!(line, format!("\u{1f600}{}", TRUNCATION_MARKER));
+        }
+
+        // Limits smaller than the marker cut without it
+        let mut line = "\u{e9}\u{e9}\u{e9}".to_string();
+        truncate_line(&mut line, 5);
+        assert_eq!(line, "\u{e9}\u{e9}");
     }
 }